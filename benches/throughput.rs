@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mqutekitty::{
+    client_handle::ClientHandle,
+    connect_packet::QoS,
+    control_packets::complete_packet_length,
+    mqtt_options::MqttOptions,
+    transport::{Connector, NetworkConnector},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    runtime::Runtime,
+};
+
+// Publishes QoS 0 messages as fast as the client takes them to a broker that only counts
+// packets, over epoll and, with the io-uring feature, over io_uring. Run it with
+//   cargo bench --features io-uring --bench throughput
+
+const PAYLOAD_SIZES: [usize; 3] = [16, 256, 4096];
+// several publishers, so packets queue up like they do on a busy gateway
+const PUBLISHERS: usize = 8;
+
+fn throughput_benchmark(c: &mut Criterion) {
+    // only the warnings, e.g. a skipped io_uring run
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .init();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("publish");
+    group.throughput(Throughput::Elements(1));
+    for payload_size in PAYLOAD_SIZES {
+        bench_connector(
+            &mut group,
+            &runtime,
+            "epoll",
+            NetworkConnector,
+            payload_size,
+        );
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        match mqutekitty::uring::probe() {
+            Ok(()) => bench_connector(
+                &mut group,
+                &runtime,
+                "io_uring",
+                mqutekitty::uring::UringConnector,
+                payload_size,
+            ),
+            Err(error) => tracing::warn!("skipped, io_uring isn't available: {}", error),
+        }
+    }
+    group.finish();
+}
+
+fn bench_connector<C: Connector + Copy + 'static>(
+    group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>,
+    runtime: &Runtime,
+    name: &str,
+    connector: C,
+    payload_size: usize,
+) {
+    group.bench_with_input(
+        BenchmarkId::new(name, payload_size),
+        &payload_size,
+        |b, &payload_size| {
+            b.to_async(runtime)
+                .iter_custom(|messages| throughput(connector, messages as usize, payload_size))
+        },
+    );
+}
+
+// How long the client takes to publish the messages, from the CONNACK until the broker read
+// the last of them
+async fn throughput(
+    connector: impl Connector + 'static,
+    messages: usize,
+    payload_size: usize,
+) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let broker = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = Vec::new();
+        let mut connected = false;
+        let mut publishes = 0;
+        while publishes < messages {
+            if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                panic!("the client closed the connection");
+            }
+            while let Some(length) = complete_packet_length(&buffer).unwrap() {
+                match connected {
+                    false => {
+                        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+                        connected = true;
+                    }
+                    true => publishes += 1,
+                }
+                buffer.drain(..length);
+            }
+        }
+    });
+
+    let client =
+        ClientHandle::with_connector(MqttOptions::new("mqutekitty-bench").unwrap(), connector);
+    client.connect(&address).await.unwrap();
+    let start = Instant::now();
+    let publishers: Vec<_> = (0..PUBLISHERS)
+        .map(|publisher| {
+            let client = client.clone();
+            // the first ones take what doesn't divide evenly
+            let count = messages / PUBLISHERS + usize::from(publisher < messages % PUBLISHERS);
+            tokio::spawn(async move {
+                let payload = vec![0x42; payload_size];
+                for _ in 0..count {
+                    client
+                        .publish("bench/throughput", &payload, QoS::AtMostOnce)
+                        .await
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for publisher in publishers {
+        publisher.await.unwrap();
+    }
+    broker.await.unwrap();
+    start.elapsed()
+}
+
+criterion_group!(benches, throughput_benchmark);
+criterion_main!(benches);
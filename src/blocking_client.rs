@@ -0,0 +1,304 @@
+use std::{io, sync::mpsc::Receiver, time::Duration};
+
+use tokio::{
+    runtime::{self, Runtime},
+    sync::broadcast,
+};
+
+use crate::{
+    client_handle::{ClientHandle, ConnectError, Connected, ConnectionEvent},
+    client_metrics::MetricsSnapshot,
+    connect_packet::QoS,
+    mqtt_options::MqttOptions,
+    publish_packet::Message,
+    router::{HandlerId, InvalidTopicFilter},
+    session_state::SessionState,
+    session_store::SessionStore,
+    sub_ack_packet::SubscribeReturnCode,
+    transport::NetworkConnector,
+};
+
+// The client for synchronous programs. It runs the async client on a runtime of its own,
+// so every method blocks the calling thread until the async one completes.
+// It must not be used from within a tokio runtime, use ClientHandle there instead.
+pub struct MyQuteKittyClient {
+    handle: ClientHandle,
+    // messages no handler took
+    messages: Receiver<Message>,
+    // declared last, so the handle is dropped before the runtime that runs its connection task
+    runtime: Runtime,
+}
+
+impl MyQuteKittyClient {
+    pub fn new(options: MqttOptions) -> io::Result<Self> {
+        MyQuteKittyClient::with_session(options, SessionState::new())
+    }
+
+    // Keeps the session in the store, so unacknowledged messages are sent again after a restart
+    pub fn with_session_store(
+        options: MqttOptions,
+        store: Box<dyn SessionStore>,
+    ) -> io::Result<Self> {
+        MyQuteKittyClient::with_session(options, SessionState::with_store(store)?)
+    }
+
+    fn with_session(options: MqttOptions, session: SessionState) -> io::Result<Self> {
+        // one worker is enough to drive the connection task while the caller is blocked
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("mqutekitty")
+            .enable_all()
+            .build()?;
+        let handle = ClientHandle::spawn(
+            options,
+            session,
+            Box::new(NetworkConnector),
+            runtime.handle(),
+        );
+        // not through on_unmatched, a catch-all set on the handle would replace it
+        let messages = handle.unmatched_messages();
+        Ok(MyQuteKittyClient {
+            handle,
+            messages,
+            runtime,
+        })
+    }
+
+    // An async handle to the same client, e.g. for a part of the program that does have a runtime.
+    // A catch-all set with its on_unmatched gets the unmatched messages as well as messages does.
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.handle.is_connected()
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.handle.metrics()
+    }
+
+    pub fn connect(&self, address: &str) -> Result<Connected, ConnectError> {
+        self.runtime.block_on(self.handle.connect(address))
+    }
+
+    pub fn connect_to_brokers(&self) -> Result<Connected, ConnectError> {
+        self.runtime.block_on(self.handle.connect_to_brokers())
+    }
+
+    // Receive with blocking_recv
+    pub fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.handle.connection_events()
+    }
+
+    pub fn disconnect(&self) -> io::Result<()> {
+        self.runtime.block_on(self.handle.disconnect())
+    }
+
+    // Waits up to the timeout for every QoS 1 and QoS 2 message to be acknowledged, disconnects
+    // and stops the client's runtime. Fails with TimedOut if some messages weren't acknowledged.
+    pub fn shutdown(self, timeout: Duration) -> io::Result<()> {
+        let result = self.runtime.block_on(self.handle.shutdown(timeout));
+        // only handler futures that are still running are left, they get a moment to finish
+        self.runtime.shutdown_timeout(timeout);
+        result
+    }
+
+    // Blocks while the in-flight window is full
+    pub fn publish(&self, topic: &str, payload: &[u8], qos: QoS) -> io::Result<()> {
+        self.runtime
+            .block_on(self.handle.publish(topic, payload, qos))
+    }
+
+    #[cfg(feature = "json")]
+    pub fn publish_json<T: serde::Serialize + ?Sized>(
+        &self,
+        topic: &str,
+        value: &T,
+        qos: QoS,
+    ) -> io::Result<()> {
+        self.runtime
+            .block_on(self.handle.publish_json(topic, value, qos))
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn publish_cbor<T: serde::Serialize + ?Sized>(
+        &self,
+        topic: &str,
+        value: &T,
+        qos: QoS,
+    ) -> io::Result<()> {
+        self.runtime
+            .block_on(self.handle.publish_cbor(topic, value, qos))
+    }
+
+    // Blocks until the response arrives, or fails with TimedOut after the timeout
+    pub fn request(&self, topic: &str, payload: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        self.runtime
+            .block_on(self.handle.request(topic, payload, timeout))
+    }
+
+    pub fn respond(&self, request: &Message, payload: &[u8]) -> io::Result<()> {
+        self.runtime.block_on(self.handle.respond(request, payload))
+    }
+
+    // Blocks until the SUBACK arrives and returns the QoS the Server granted
+    pub fn subscribe(&self, topic: &str, qos: QoS) -> io::Result<SubscribeReturnCode> {
+        let return_codes = self.subscribe_many(&[(topic, qos)])?;
+        Ok(return_codes[0])
+    }
+
+    pub fn subscribe_many(
+        &self,
+        topic_filters: &[(&str, QoS)],
+    ) -> io::Result<Vec<SubscribeReturnCode>> {
+        let suback = self.handle.subscribe_many(topic_filters)?;
+        Ok(self.runtime.block_on(suback)?)
+    }
+
+    // Blocks until the UNSUBACK arrives
+    pub fn unsubscribe(&self, topic: &str) -> io::Result<()> {
+        let unsuback = self.handle.unsubscribe(topic)?;
+        Ok(self.runtime.block_on(unsuback)?)
+    }
+
+    // The handler runs on the client's own thread, so it should return quickly.
+    // Messages no handler matches are returned by messages.
+    pub fn on<F>(&self, filter: &str, handler: F) -> Result<HandlerId, InvalidTopicFilter>
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        self.handle.on(filter, handler)
+    }
+
+    // With unsubscribe set, also unsubscribes the filter when its last handler is removed
+    // and blocks until the UNSUBACK arrives
+    pub fn remove_handler(&self, handler_id: HandlerId, unsubscribe: bool) -> io::Result<()> {
+        match self.handle.remove_handler(handler_id, unsubscribe)? {
+            Some(unsuback) => Ok(self.runtime.block_on(unsuback)?),
+            None => Ok(()),
+        }
+    }
+
+    // Blocks until the next message arrives, for as long as the client exists.
+    // Every message no handler matched arrives here, even if handle().on_unmatched set a catch-all.
+    pub fn messages(&self) -> impl Iterator<Item = Message> + '_ {
+        self.messages.iter()
+    }
+
+    // Returns a message that already arrived, without blocking
+    pub fn next_message(&self) -> Option<Message> {
+        self.messages.try_recv().ok()
+    }
+
+    pub fn next_message_timeout(&self, timeout: Duration) -> Option<Message> {
+        self.messages.recv_timeout(timeout).ok()
+    }
+}
+
+#[cfg(test)]
+mod blocking_client_tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        connect_packet::QoS,
+        control_packets::{complete_packet_length, ControlPacketType},
+        mqtt_options::MqttOptions,
+        sub_ack_packet::SubscribeReturnCode,
+    };
+
+    use super::MyQuteKittyClient;
+
+    // Just enough of a broker to drive the client from a test, on a thread of its own
+    struct FakeBroker {
+        stream: TcpStream,
+        buffer: Vec<u8>,
+    }
+
+    impl FakeBroker {
+        // Reads the CONNECT and accepts it
+        fn accept(listener: &TcpListener) -> FakeBroker {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut broker = FakeBroker {
+                stream,
+                buffer: Vec::new(),
+            };
+            let connect = broker.read_packet();
+            assert_eq!(
+                ControlPacketType::from(connect[0] >> 4),
+                ControlPacketType::Connect
+            );
+            broker.write(&[0x20, 0x02, 0x00, 0x00]);
+            broker
+        }
+
+        fn read_packet(&mut self) -> Vec<u8> {
+            loop {
+                if let Some(length) = complete_packet_length(&self.buffer).unwrap() {
+                    return self.buffer.drain(..length).collect();
+                }
+                let mut chunk = [0u8; 1024];
+                let read = self.stream.read(&mut chunk).unwrap();
+                assert_ne!(read, 0, "the client closed the connection");
+                self.buffer.extend_from_slice(&chunk[..read]);
+            }
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            self.stream.write_all(bytes).unwrap();
+        }
+    }
+
+    #[test]
+    fn blocking_round_trip_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let broker = thread::spawn(move || {
+            let mut broker = FakeBroker::accept(&listener);
+
+            let subscribe = broker.read_packet();
+            assert_eq!(subscribe[0], 0x82);
+            broker.write(&[0x90, 0x03, subscribe[2], subscribe[3], 0x00]);
+
+            let publish = broker.read_packet();
+            assert_eq!(publish[0], 0x30);
+            // echo it back, the client is subscribed
+            broker.write(&publish);
+
+            let disconnect = broker.read_packet();
+            assert_eq!(disconnect, vec![0xe0, 0x00]);
+        });
+
+        let client = MyQuteKittyClient::new(MqttOptions::new("mqutekitty").unwrap()).unwrap();
+        // doesn't take the messages away from messages()
+        let (caught_sender, caught) = mpsc::channel();
+        client.handle().on_unmatched(move |message| {
+            let _ = caught_sender.send(message.topic.clone());
+        });
+        client.connect(&address).unwrap();
+        assert!(client.is_connected());
+        assert_eq!(
+            client.subscribe("a/b", QoS::AtMostOnce).unwrap(),
+            SubscribeReturnCode::Success(QoS::AtMostOnce)
+        );
+        client.publish("a/b", b"echo", QoS::AtMostOnce).unwrap();
+
+        let message = client.messages().next().unwrap();
+        assert_eq!(message.topic, "a/b");
+        assert_eq!(message.payload, b"echo");
+        assert!(client.next_message().is_none());
+        assert_eq!(caught.recv_timeout(Duration::from_secs(5)).unwrap(), "a/b");
+
+        client.shutdown(Duration::from_secs(5)).unwrap();
+        broker.join().unwrap();
+    }
+}
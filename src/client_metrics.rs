@@ -0,0 +1,218 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::control_packets::ControlPacketType;
+
+// Counters the connection task keeps while it runs. A MetricsSnapshot is a copy of them together
+// with the current in-flight count and queue depth.
+// With the metrics feature everything is also reported through the metrics crate, as
+//   mqtt_packets_sent_total, mqtt_bytes_sent_total,
+//   mqtt_packets_received_total, mqtt_bytes_received_total (labelled with the packet_type),
+//   mqtt_in_flight, mqtt_queue_depth, mqtt_reconnects_total and mqtt_ping_rtt_seconds.
+
+// one slot for each value of the 4 bit packet type
+const PACKET_TYPES: usize = 16;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct PacketCounters {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MetricsSnapshot {
+    sent: [PacketCounters; PACKET_TYPES],
+    received: [PacketCounters; PACKET_TYPES],
+    // QoS 1 and QoS 2 publishes waiting for their acknowledgement
+    pub in_flight: usize,
+    // publishes waiting in the offline queue
+    pub queue_depth: usize,
+    // successful connects after the first one
+    pub reconnects: u64,
+    // time between the last PINGREQ and its PINGRESP
+    pub ping_rtt: Option<Duration>,
+}
+
+impl MetricsSnapshot {
+    pub fn sent(&self, packet_type: ControlPacketType) -> PacketCounters {
+        self.sent[packet_type as usize]
+    }
+
+    pub fn received(&self, packet_type: ControlPacketType) -> PacketCounters {
+        self.received[packet_type as usize]
+    }
+
+    pub fn total_sent(&self) -> PacketCounters {
+        total(&self.sent)
+    }
+
+    pub fn total_received(&self) -> PacketCounters {
+        total(&self.received)
+    }
+}
+
+fn total(counters: &[PacketCounters]) -> PacketCounters {
+    counters
+        .iter()
+        .fold(PacketCounters::default(), |total, counters| {
+            PacketCounters {
+                packets: total.packets + counters.packets,
+                bytes: total.bytes + counters.bytes,
+            }
+        })
+}
+
+#[derive(Debug, Default)]
+struct AtomicPacketCounters {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl AtomicPacketCounters {
+    fn load(&self) -> PacketCounters {
+        PacketCounters {
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    sent: [AtomicPacketCounters; PACKET_TYPES],
+    received: [AtomicPacketCounters; PACKET_TYPES],
+    connects: AtomicU64,
+    // in microseconds, 0 until the first PINGRESP
+    ping_rtt: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // Takes the encoded packet, its type is in the first byte
+    pub fn packet_sent(&self, packet_bytes: &[u8]) {
+        record(&self.sent, packet_bytes);
+        #[cfg(feature = "metrics")]
+        report_packet("sent", packet_bytes);
+    }
+
+    pub fn packet_received(&self, packet_bytes: &[u8]) {
+        record(&self.received, packet_bytes);
+        #[cfg(feature = "metrics")]
+        report_packet("received", packet_bytes);
+    }
+
+    pub fn connected(&self) {
+        let previous_connects = self.connects.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if previous_connects > 0 {
+            ::metrics::counter!("mqtt_reconnects_total").increment(1);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = previous_connects;
+    }
+
+    pub fn ping_answered(&self, rtt: Duration) {
+        // never 0, that means no answer yet
+        let micros = (rtt.as_micros() as u64).max(1);
+        self.ping_rtt.store(micros, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        ::metrics::histogram!("mqtt_ping_rtt_seconds").record(rtt.as_secs_f64());
+    }
+
+    // The window and the queue are owned elsewhere, so their sizes are passed in
+    pub fn queue_sizes(&self, in_flight: usize, queue_depth: usize) {
+        #[cfg(feature = "metrics")]
+        {
+            ::metrics::gauge!("mqtt_in_flight").set(in_flight as f64);
+            ::metrics::gauge!("mqtt_queue_depth").set(queue_depth as f64);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (in_flight, queue_depth);
+    }
+
+    pub fn snapshot(&self, in_flight: usize, queue_depth: usize) -> MetricsSnapshot {
+        let ping_rtt = match self.ping_rtt.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        };
+        MetricsSnapshot {
+            sent: std::array::from_fn(|index| self.sent[index].load()),
+            received: std::array::from_fn(|index| self.received[index].load()),
+            in_flight,
+            queue_depth,
+            reconnects: self.connects.load(Ordering::Relaxed).saturating_sub(1),
+            ping_rtt,
+        }
+    }
+}
+
+fn record(counters: &[AtomicPacketCounters; PACKET_TYPES], packet_bytes: &[u8]) {
+    if let Some(first_byte) = packet_bytes.first() {
+        let counters = &counters[(first_byte >> 4) as usize];
+        counters.packets.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes
+            .fetch_add(packet_bytes.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn report_packet(direction: &'static str, packet_bytes: &[u8]) {
+    if let Some(first_byte) = packet_bytes.first() {
+        let packet_type = format!("{:?}", ControlPacketType::from(first_byte >> 4));
+        let (packets, bytes) = match direction {
+            "sent" => ("mqtt_packets_sent_total", "mqtt_bytes_sent_total"),
+            _ => ("mqtt_packets_received_total", "mqtt_bytes_received_total"),
+        };
+        ::metrics::counter!(packets, "packet_type" => packet_type.clone()).increment(1);
+        ::metrics::counter!(bytes, "packet_type" => packet_type)
+            .increment(packet_bytes.len() as u64);
+    }
+}
+
+#[cfg(test)]
+mod client_metrics_tests {
+    use std::time::Duration;
+
+    use crate::control_packets::ControlPacketType;
+
+    use super::{Metrics, PacketCounters};
+
+    #[test]
+    fn snapshot_test() {
+        let metrics = Metrics::new();
+        metrics.packet_sent(&[0x30, 0x05, 0x00, 0x01, b'a', b'h', b'i']);
+        metrics.packet_sent(&[0x30, 0x03, 0x00, 0x01, b'a']);
+        metrics.packet_sent(&[0xc0, 0x00]);
+        metrics.packet_received(&[0xd0, 0x00]);
+        metrics.connected();
+
+        let snapshot = metrics.snapshot(1, 2);
+        assert_eq!(
+            snapshot.sent(ControlPacketType::Publish),
+            PacketCounters {
+                packets: 2,
+                bytes: 12
+            }
+        );
+        assert_eq!(snapshot.sent(ControlPacketType::PingReq).packets, 1);
+        assert_eq!(snapshot.received(ControlPacketType::PingResp).bytes, 2);
+        assert_eq!(snapshot.total_sent().packets, 3);
+        assert_eq!(snapshot.total_received().packets, 1);
+        assert_eq!(snapshot.in_flight, 1);
+        assert_eq!(snapshot.queue_depth, 2);
+        assert_eq!(snapshot.reconnects, 0);
+        assert_eq!(snapshot.ping_rtt, None);
+
+        metrics.connected();
+        metrics.ping_answered(Duration::from_millis(3));
+        let snapshot = metrics.snapshot(0, 0);
+        assert_eq!(snapshot.reconnects, 1);
+        assert_eq!(snapshot.ping_rtt, Some(Duration::from_millis(3)));
+    }
+}
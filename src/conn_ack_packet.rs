@@ -1,6 +1,33 @@
-use crate::control_packets::{
-    encode_remaining_length, ControlPacketFlags, ControlPacketType, FixedHeader,
-};
+use std::io;
+
+use crate::control_packets::{malformed, ControlPacketFlags, ControlPacketType, FixedHeader};
+
+// 3.2.2.3 Connect Return code
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ConnectReturnCode {
+    Accepted,
+    UnacceptableProtocolVersion,
+    IdentifierRejected,
+    ServerUnavailable,
+    BadUserNameOrPassword,
+    NotAuthorized,
+    // 6-255 are reserved
+    Reserved(u8),
+}
+
+impl From<u8> for ConnectReturnCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => ConnectReturnCode::Accepted,
+            1 => ConnectReturnCode::UnacceptableProtocolVersion,
+            2 => ConnectReturnCode::IdentifierRejected,
+            3 => ConnectReturnCode::ServerUnavailable,
+            4 => ConnectReturnCode::BadUserNameOrPassword,
+            5 => ConnectReturnCode::NotAuthorized,
+            reserved => ConnectReturnCode::Reserved(reserved),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ConnAck {
@@ -9,23 +36,39 @@ pub struct ConnAck {
     pub connect_return_code: u8,
 }
 
-impl From<&[u8]> for ConnAck {
-    fn from(bytes: &[u8]) -> Self {
-        let fixed_header = FixedHeader::from(bytes);
-        let remaining_length_byte_count = encode_remaining_length(fixed_header.remaining_length)
-            .unwrap()
-            .len();
-        let connect_ack_flags_index = 1 + remaining_length_byte_count;
-        let connect_return_code_index = connect_ack_flags_index + 1;
-        Self {
-            fixed_header: FixedHeader::from(bytes),
-            connect_ack_flags: bytes[connect_ack_flags_index],
-            connect_return_code: bytes[connect_return_code_index],
+impl TryFrom<&[u8]> for ConnAck {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, variable_header) = FixedHeader::split(bytes)?;
+        match *variable_header {
+            [connect_ack_flags, connect_return_code, ..] => Ok(Self {
+                fixed_header,
+                connect_ack_flags,
+                connect_return_code,
+            }),
+            _ => Err(malformed("CONNACK")),
         }
     }
 }
 
+impl Default for ConnAck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConnAck {
+    const SESSION_PRESENT_MASK: u8 = 0b0000_0001;
+
+    pub fn return_code(&self) -> ConnectReturnCode {
+        ConnectReturnCode::from(self.connect_return_code)
+    }
+
+    pub fn session_present(&self) -> bool {
+        self.connect_ack_flags & ConnAck::SESSION_PRESENT_MASK != 0
+    }
+
     pub fn new() -> Self {
         Self {
             fixed_header: FixedHeader {
@@ -38,3 +81,23 @@ impl ConnAck {
         }
     }
 }
+
+#[cfg(test)]
+mod conn_ack_packet_tests {
+    use super::{ConnAck, ConnectReturnCode};
+
+    #[test]
+    fn decode_test() {
+        let conn_ack = ConnAck::try_from(&[0x20u8, 0x02, 0x01, 0x00][..]).unwrap();
+        assert!(conn_ack.session_present());
+        assert_eq!(conn_ack.return_code(), ConnectReturnCode::Accepted);
+
+        let conn_ack = ConnAck::try_from(&[0x20u8, 0x02, 0x00, 0x04][..]).unwrap();
+        assert!(!conn_ack.session_present());
+        assert_eq!(
+            conn_ack.return_code(),
+            ConnectReturnCode::BadUserNameOrPassword
+        );
+        assert_eq!(ConnectReturnCode::from(9), ConnectReturnCode::Reserved(9));
+    }
+}
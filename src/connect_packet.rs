@@ -34,7 +34,7 @@ pub struct ProtocolName<'a> {
     pub name: &'a str,
 }
 
-const MQTT_PROTOCOL_NAME: &'static str = "MQTT";
+const MQTT_PROTOCOL_NAME: &str = "MQTT";
 
 // 3.1.2.1. Protocol Level
 //  ---------------------------------------------------------
@@ -81,9 +81,9 @@ impl From<u8> for ConnectFlags {
     }
 }
 
-impl Into<u8> for ConnectFlags {
-    fn into(self) -> u8 {
-        self.byte_rep
+impl From<ConnectFlags> for u8 {
+    fn from(val: ConnectFlags) -> Self {
+        val.byte_rep
     }
 }
 
@@ -105,33 +105,33 @@ impl ConnectFlagsBuilder {
     }
 
     pub fn user_name(&mut self) -> &mut Self {
-        self.byte_rep = self.byte_rep | ConnectFlagsBuilder::USER_NAME_MASK;
+        self.byte_rep |= ConnectFlagsBuilder::USER_NAME_MASK;
         self
     }
 
     pub fn password(&mut self) -> &mut Self {
-        self.byte_rep = self.byte_rep | ConnectFlagsBuilder::PASSWORD_MASK;
+        self.byte_rep |= ConnectFlagsBuilder::PASSWORD_MASK;
         self
     }
 
     pub fn will_retain(&mut self) -> &mut Self {
-        self.byte_rep = self.byte_rep | ConnectFlagsBuilder::WILL_RETAIN_MASK;
+        self.byte_rep |= ConnectFlagsBuilder::WILL_RETAIN_MASK;
         self
     }
 
     pub fn will_qos(&mut self, qos: u8) -> &mut Self {
         assert!(qos < 3);
-        self.byte_rep = self.byte_rep | ((qos << 3u8) & ConnectFlagsBuilder::WILL_QOS_MASK);
+        self.byte_rep |= (qos << 3u8) & ConnectFlagsBuilder::WILL_QOS_MASK;
         self
     }
 
     pub fn will_flag(&mut self) -> &mut Self {
-        self.byte_rep = self.byte_rep | ConnectFlagsBuilder::WILL_FLAG_MASK;
+        self.byte_rep |= ConnectFlagsBuilder::WILL_FLAG_MASK;
         self
     }
 
     pub fn clean_session(&mut self) -> &mut Self {
-        self.byte_rep = self.byte_rep | ConnectFlagsBuilder::CLEAN_SESSION_MASK;
+        self.byte_rep |= ConnectFlagsBuilder::CLEAN_SESSION_MASK;
         self
     }
 
@@ -246,7 +246,7 @@ impl fmt::Display for ErrorBuildingConnectPacket {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum QoS {
     AtMostOnce = 0,
@@ -292,6 +292,12 @@ pub struct Builder {
 const U16_SIZE_IN_BYTES: usize = 2;
 const U8_SIZE_IN_BYTES: usize = 1;
 
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Builder {
     pub fn new() -> Self {
         Builder {
@@ -359,52 +365,54 @@ impl Builder {
         self
     }
 
+    fn has_will(&self) -> bool {
+        self.will_message.is_some() && self.will_topic.is_some()
+    }
+
+    // A password is only sent together with a user name [MQTT-3.1.2-22]
+    fn has_password(&self) -> bool {
+        self.password.is_some() && self.user_name.is_some()
+    }
+
     pub fn calc_flags(&self) -> u8 {
         let mut flags = 0b0000_0000;
-        flags = flags
-            | if self.user_name.is_some() {
-                ConnectFlagsBuilder::USER_NAME_MASK
-            } else {
-                0
-            };
-        flags = flags
-            | if self.password.is_some() && self.user_name.is_some() {
-                ConnectFlagsBuilder::PASSWORD_MASK
-            } else {
-                0
-            };
-        flags = flags
-            | if self.will_retain == true {
+        flags |= if self.user_name.is_some() {
+            ConnectFlagsBuilder::USER_NAME_MASK
+        } else {
+            0
+        };
+        flags |= if self.has_password() {
+            ConnectFlagsBuilder::PASSWORD_MASK
+        } else {
+            0
+        };
+        // If the Will Flag is set to 0, then Will QoS and Will Retain MUST be set to 0 [MQTT-3.1.2-13] [MQTT-3.1.2-15]
+        if self.has_will() {
+            flags |= ConnectFlagsBuilder::WILL_FLAG_MASK;
+            flags |= if self.will_retain {
                 ConnectFlagsBuilder::WILL_RETAIN_MASK
             } else {
                 0
             };
 
-        let will_qos = match self.will_qos {
-            Some(qos) => qos,
-            None => QoS::AtLeastOnce,
-        };
-        flags = flags | ((will_qos as u8) << 3u8) & ConnectFlagsBuilder::WILL_QOS_MASK;
-
-        flags = flags
-            | if self.will_message.is_some() && self.will_topic.is_some() {
-                ConnectFlagsBuilder::WILL_FLAG_MASK
-            } else {
-                0
-            };
-        flags = flags
-            | if self.clean_session {
-                ConnectFlagsBuilder::CLEAN_SESSION_MASK
-            } else {
-                0
+            let will_qos = match self.will_qos {
+                Some(qos) => qos,
+                None => QoS::AtLeastOnce,
             };
+            flags |= ((will_qos as u8) << 3u8) & ConnectFlagsBuilder::WILL_QOS_MASK;
+        }
+        flags |= if self.clean_session {
+            ConnectFlagsBuilder::CLEAN_SESSION_MASK
+        } else {
+            0
+        };
         flags
     }
 
     pub fn calc_remaining_length(&self) -> usize {
         let mut remaining_length = 0;
         // Variable header - protocol name: 2 bytes for the length prefix + string length
-        remaining_length += U16_SIZE_IN_BYTES + &self.protocol_name.len();
+        remaining_length += U16_SIZE_IN_BYTES + self.protocol_name.len();
         // Variable header - byte 7 - protocol level
         remaining_length += U8_SIZE_IN_BYTES;
         // Variable header - byte 8 - connect flags
@@ -413,23 +421,25 @@ impl Builder {
         // Variable header - byte 10 - keep alive LSB
         remaining_length += U16_SIZE_IN_BYTES;
 
-        // Payload - client id - first entry
+        // Payload - client id - first entry, always present even if it is empty
         remaining_length += match &self.client_id {
             Some(client_id) => U16_SIZE_IN_BYTES + client_id.len(),
-            None => 0,
+            None => U16_SIZE_IN_BYTES,
         };
 
-        // Payload - will topic - second entry
-        remaining_length += match &self.will_topic {
-            Some(will_topic) => U16_SIZE_IN_BYTES + will_topic.len(),
-            None => 0,
-        };
+        if self.has_will() {
+            // Payload - will topic - second entry
+            remaining_length += match &self.will_topic {
+                Some(will_topic) => U16_SIZE_IN_BYTES + will_topic.len(),
+                None => 0,
+            };
 
-        // Payload - will message - third entry
-        remaining_length += match &self.will_message {
-            Some(will_message) => U16_SIZE_IN_BYTES + will_message.len(),
-            None => 0,
-        };
+            // Payload - will message - third entry
+            remaining_length += match &self.will_message {
+                Some(will_message) => U16_SIZE_IN_BYTES + will_message.len(),
+                None => 0,
+            };
+        }
 
         // Payload - user name - fourth entry
         remaining_length += match &self.user_name {
@@ -438,16 +448,20 @@ impl Builder {
         };
 
         // Payload - password - fifth entry
-        remaining_length += match &self.password {
-            Some(password) => U16_SIZE_IN_BYTES + password.len(),
-            None => 0,
-        };
+        if self.has_password() {
+            remaining_length += match &self.password {
+                Some(password) => U16_SIZE_IN_BYTES + password.len(),
+                None => 0,
+            };
+        }
         remaining_length
     }
 
     pub fn build(&mut self) -> Result<ConnectPacket, Box<dyn Error>> {
         let remaining_length = self.calc_remaining_length();
         let flags = self.calc_flags();
+        let has_will = self.has_will();
+        let has_password = self.has_password();
 
         Ok(ConnectPacket {
             fixed_header: FixedHeader {
@@ -459,7 +473,23 @@ impl Builder {
             protocol_level: self.protocol_level.take().unwrap(),
             connect_flags: flags.into(),
             keep_alive: self.keep_alive_interval.as_secs().try_into()?, // max keep alive seconds is 65535
-            client_id: self.client_id.take().unwrap(), // todo: I'm not sure if this is a good idea
+            client_id: self.client_id.take().unwrap_or_default(),
+            will_topic: if has_will {
+                self.will_topic.take()
+            } else {
+                None
+            },
+            will_message: if has_will {
+                self.will_message.take()
+            } else {
+                None
+            },
+            password: if has_password {
+                self.password.take()
+            } else {
+                None
+            },
+            user_name: self.user_name.take(),
         })
     }
 }
@@ -471,22 +501,37 @@ pub struct ConnectPacket {
     pub connect_flags: ConnectFlags,
     pub keep_alive: u16,
     pub client_id: String,
+    pub will_topic: Option<String>,
+    pub will_message: Option<String>,
+    pub user_name: Option<String>,
+    pub password: Option<String>,
 }
 
 impl ConnectPacket {
     pub fn encode(&self) -> Vec<u8> {
         let mut vec: Vec<u8> = Vec::new();
-        vec.extend_from_slice(&mut self.fixed_header.encode());
+        vec.extend_from_slice(&self.fixed_header.encode());
 
         // Variable Header
         vec.extend_from_slice(&(self.protocol_name.len() as u16).to_be_bytes());
-        vec.extend_from_slice(&mut vec![b'M', b'Q', b'T', b'T']);
+        vec.extend_from_slice(b"MQTT");
         vec.push(self.protocol_level as u8); // vh byte 7
         vec.push(self.connect_flags.into()); // vh byte 8 - connected flags
         vec.extend_from_slice(&self.keep_alive.to_be_bytes());
-        vec.extend_from_slice(&(self.client_id.len() as u16).to_be_bytes());
-        vec.extend_from_slice(&self.client_id.as_bytes());
-        return vec;
+
+        // Payload, in the order mandated by [MQTT-3.1.3-1]
+        let payload_fields = [
+            Some(&self.client_id),
+            self.will_topic.as_ref(),
+            self.will_message.as_ref(),
+            self.user_name.as_ref(),
+            self.password.as_ref(),
+        ];
+        for field in payload_fields.into_iter().flatten() {
+            vec.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            vec.extend_from_slice(field.as_bytes());
+        }
+        vec
     }
 }
 
@@ -560,4 +605,48 @@ mod connect_packet_tests {
         assert_eq!(connect_packet_bytes[29], 110); // 'n'
         assert_eq!(connect_packet_bytes[30], 116); // 't'
     }
+
+    #[test]
+    fn encode_payload_test() {
+        let connect_packet = connect_packet::Builder::new()
+            .client_id("c")
+            .will_topic("w/t")
+            .will_message("bye")
+            .will_qos(1)
+            .will_retain()
+            .user_name(String::from("user"))
+            .password(String::from("pw"))
+            .build()
+            .unwrap();
+        let connect_packet_bytes: Vec<u8> = connect_packet.encode();
+
+        assert_eq!(
+            connect_packet_bytes[1] as usize,
+            connect_packet_bytes.len() - 2
+        );
+        assert_eq!(connect_packet_bytes[9], 0b1110_1100); // connect flags
+        assert_eq!(
+            &connect_packet_bytes[12..],
+            b"\x00\x01c\x00\x03w/t\x00\x03bye\x00\x04user\x00\x02pw"
+        );
+    }
+
+    #[test]
+    fn password_without_user_name_test() {
+        let connect_packet = connect_packet::Builder::new()
+            .client_id("c")
+            .password(String::from("pw"))
+            .will_qos(2)
+            .build()
+            .unwrap();
+        let connect_packet_bytes: Vec<u8> = connect_packet.encode();
+
+        // neither the password nor the will qos make it into the packet
+        assert_eq!(connect_packet_bytes[9], 0);
+        assert_eq!(
+            connect_packet_bytes[1] as usize,
+            connect_packet_bytes.len() - 2
+        );
+        assert_eq!(&connect_packet_bytes[12..], b"\x00\x01c");
+    }
 }
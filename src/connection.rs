@@ -0,0 +1,825 @@
+use std::{future, io, sync::atomic::Ordering, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc::UnboundedReceiver, oneshot},
+    time::{self, Instant, Interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    broker_url::BrokerUrl,
+    client_handle::{ConnectError, Connected, ConnectionEvent, Shared},
+    conn_ack_packet::ConnAck,
+    connect_packet::QoS,
+    control_packets::{complete_packet_length, ControlPacketType, Encodable, FixedHeader},
+    disconnect_packet::DisconnectPacket,
+    failover::Failover,
+    ping_packets::{PingReqPacket, PingRespPacket},
+    pub_ack_packets::{PubAckPacket, PubCompPacket, PubRecPacket, PubRelPacket},
+    publish_packet::{self, Message, PublishPacket, PublishPacketFlags},
+    router,
+    sub_ack_packet::SubAckPacket,
+    transport::{BoxedTransport, Connector},
+    unsub_ack_packet::UnsubAckPacket,
+};
+
+// What the handles ask the connection task to do
+pub(crate) enum Command {
+    Connect {
+        address: String,
+        reply: oneshot::Sender<Result<Connected, ConnectError>>,
+    },
+    // connects to the options' brokers, and reconnects to them whenever the connection is lost
+    ConnectToBrokers {
+        reply: oneshot::Sender<Result<Connected, ConnectError>>,
+    },
+    // holds_slot is set when the handle took a slot of the in-flight window for the message
+    Publish {
+        message: Message,
+        holds_slot: bool,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+    // a SUBSCRIBE or UNSUBSCRIBE whose acknowledgement is already registered in the pending acks
+    Send {
+        packet_id: u16,
+        packet_bytes: Vec<u8>,
+    },
+    Ping,
+    Disconnect {
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+    // disconnects once every QoS 1 and QoS 2 message is acknowledged, or the timeout passed,
+    // and stops the task
+    Shutdown {
+        timeout: Duration,
+        reply: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+struct Draining {
+    deadline: Instant,
+    reply: oneshot::Sender<io::Result<()>>,
+}
+
+struct Reconnecting {
+    // failed attempts so far, the delay doubles with each
+    attempts: u32,
+    at: Instant,
+}
+
+// The only owner of the socket. It runs as a single task that writes whatever the handles
+// send over the command channel and reads and answers whatever the Server sends, until every
+// handle is dropped.
+pub(crate) struct Connection {
+    shared: Arc<Shared>,
+    commands: UnboundedReceiver<Command>,
+    // opens the transport on every connect
+    connector: Box<dyn Connector>,
+    stream: Option<BoxedTransport>,
+    // bytes received that don't make up a whole packet yet
+    read_buffer: Vec<u8>,
+    ping_timer: Option<Interval>,
+    // set while shutting down
+    draining: Option<Draining>,
+    // when the PINGREQ still waiting for its PINGRESP went out
+    ping_sent_at: Option<Instant>,
+    // what the connection was opened to, as given to connect
+    endpoint: Option<String>,
+    // packets were written without a flush because more commands were waiting
+    unflushed: bool,
+    // which of the options' brokers to try first
+    failover: Failover,
+    // set while the connection was opened to the options' brokers, a lost one is reopened
+    follow_brokers: bool,
+    // set while waiting to reconnect to the brokers
+    reconnecting: Option<Reconnecting>,
+}
+
+impl Connection {
+    pub fn new(
+        shared: Arc<Shared>,
+        commands: UnboundedReceiver<Command>,
+        connector: Box<dyn Connector>,
+    ) -> Self {
+        let options = &shared.options;
+        let failover = Failover::new(options.failover_policy(), options.brokers().len());
+        Connection {
+            shared,
+            commands,
+            connector,
+            stream: None,
+            read_buffer: Vec::new(),
+            ping_timer: None,
+            draining: None,
+            ping_sent_at: None,
+            endpoint: None,
+            unflushed: false,
+            failover,
+            follow_brokers: false,
+            reconnecting: None,
+        }
+    }
+
+    pub async fn run(mut self) {
+        self.serve().await;
+        // publishes that wait for a slot would never get one
+        self.shared.in_flight_window.close();
+    }
+
+    async fn serve(&mut self) {
+        loop {
+            if self.draining.is_some() && (self.stream.is_none() || self.drained()) {
+                self.finish_shutdown().await;
+                return;
+            }
+            if self.unflushed && self.commands.is_empty() {
+                if let Err(error) = self.flush().await {
+                    self.connection_lost(error);
+                }
+            }
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                read = read_some(&mut self.stream, &mut self.read_buffer) => {
+                    if let Err(error) = self.handle_read(read).await {
+                        self.connection_lost(error);
+                    }
+                },
+                _ = next_tick(&mut self.ping_timer) => {
+                    if let Err(error) = self.write_packet(&PingReqPacket::new().encode()).await {
+                        self.connection_lost(error);
+                    }
+                },
+                _ = drain_deadline(&self.draining) => {
+                    self.finish_shutdown().await;
+                    return;
+                },
+                _ = reconnect_deadline(&self.reconnecting) => self.reconnect().await,
+            }
+            self.report_queue_sizes();
+        }
+        debug!("Every client handle is gone, stopping the connection task");
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Connect { address, reply } => {
+                self.stop_following_brokers();
+                let result = self.connect_and_report(&address).await;
+                let _ = reply.send(result);
+            }
+            Command::ConnectToBrokers { reply } => {
+                self.reconnecting = None;
+                let result = self.connect_to_brokers().await;
+                self.follow_brokers = result.is_ok();
+                let _ = reply.send(result);
+            }
+            Command::Publish {
+                message,
+                holds_slot,
+                reply,
+            } => {
+                if self.draining.is_some() {
+                    if holds_slot {
+                        self.shared.in_flight_window.release();
+                    }
+                    let _ = reply.send(Err(shutting_down()));
+                    return;
+                }
+                let result = self.publish(message, holds_slot).await;
+                let _ = reply.send(result);
+            }
+            Command::Send {
+                packet_id,
+                packet_bytes,
+            } => {
+                if self.stream.is_none() {
+                    self.shared.abandon(packet_id);
+                    return;
+                }
+                if let Err(error) = self.write_packet(&packet_bytes).await {
+                    self.connection_lost(error);
+                }
+            }
+            Command::Ping => {
+                if let Err(error) = self.write_packet(&PingReqPacket::new().encode()).await {
+                    self.connection_lost(error);
+                }
+            }
+            Command::Disconnect { reply } => {
+                self.stop_following_brokers();
+                let result = self.write_packet(&DisconnectPacket::new().encode()).await;
+                if let Some(stream) = &mut self.stream {
+                    let _ = stream.shutdown().await;
+                }
+                self.close();
+                let _ = reply.send(result);
+            }
+            Command::Shutdown { timeout, reply } => {
+                self.stop_following_brokers();
+                self.draining = Some(Draining {
+                    deadline: Instant::now() + timeout,
+                    reply,
+                });
+            }
+        }
+    }
+
+    // Nothing is left to acknowledge and nothing is waiting to be sent
+    fn drained(&self) -> bool {
+        self.shared.session.lock().unwrap().outgoing_count() == 0
+            && self.shared.offline_queue.lock().unwrap().is_empty()
+    }
+
+    async fn finish_shutdown(&mut self) {
+        let unacknowledged = self.shared.session.lock().unwrap().outgoing_count()
+            + self.shared.offline_queue.lock().unwrap().len();
+        if self.stream.is_some() {
+            if let Err(error) = self.write_packet(&DisconnectPacket::new().encode()).await {
+                warn!("Failed to send the DISCONNECT: {}", error);
+            }
+            if let Some(stream) = &mut self.stream {
+                let _ = stream.shutdown().await;
+            }
+        }
+        self.close();
+
+        let result = match unacknowledged {
+            0 => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "{} messages weren't acknowledged before the shutdown",
+                    unacknowledged
+                ),
+            )),
+        };
+        if let Some(draining) = self.draining.take() {
+            let _ = draining.reply.send(result);
+        }
+        debug!("Shut down, stopping the connection task");
+    }
+
+    // Connects and reports the outcome as a connection event
+    async fn connect_and_report(&mut self, address: &str) -> Result<Connected, ConnectError> {
+        let result = self.connect(address).await;
+        match &result {
+            Ok(connected) => {
+                self.endpoint = Some(address.to_string());
+                self.report(ConnectionEvent::Connected {
+                    endpoint: address.to_string(),
+                    session_present: connected.session_present,
+                    remote_address: connected.remote_address,
+                });
+            }
+            Err(error) => {
+                error!("Failed to connect to {}: {}", address, error);
+                self.close();
+                self.report(ConnectionEvent::ConnectFailed {
+                    endpoint: address.to_string(),
+                    error: error.to_string(),
+                });
+            }
+        }
+        result
+    }
+
+    // Tries the options' brokers in the order of the failover policy until one accepts the
+    // connection. Fails with the error of the last broker if none does.
+    async fn connect_to_brokers(&mut self) -> Result<Connected, ConnectError> {
+        let brokers = self.shared.options.brokers().to_vec();
+        let mut last_error = ConnectError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the options list no brokers",
+        ));
+        for index in self.failover.order() {
+            match self.connect_and_report(&brokers[index]).await {
+                Ok(connected) => {
+                    self.failover.connected(index);
+                    return Ok(connected);
+                }
+                Err(error) => {
+                    warn!("Failing over from {}: {}", brokers[index], error);
+                    last_error = error;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn reconnect(&mut self) {
+        let attempts = match self.reconnecting.take() {
+            Some(reconnecting) => reconnecting.attempts,
+            None => return,
+        };
+        info!("Reconnecting to the brokers");
+        if self.connect_to_brokers().await.is_err() {
+            self.schedule_reconnect(attempts + 1);
+        }
+    }
+
+    // Waits the reconnect delay, doubled for every failed attempt, up to the max
+    fn schedule_reconnect(&mut self, attempts: u32) {
+        let options = &self.shared.options;
+        let delay = options
+            .reconnect_delay()
+            .saturating_mul(1 << attempts.min(16))
+            .min(options.max_reconnect_delay().max(options.reconnect_delay()));
+        debug!("Reconnecting in {:?}", delay);
+        self.reconnecting = Some(Reconnecting {
+            attempts,
+            at: Instant::now() + delay,
+        });
+    }
+
+    fn stop_following_brokers(&mut self) {
+        self.follow_brokers = false;
+        self.reconnecting = None;
+    }
+
+    async fn connect(&mut self, address: &str) -> Result<Connected, ConnectError> {
+        self.close();
+
+        let (address, options) = match address.contains("://") {
+            true => {
+                let url = BrokerUrl::parse(address)?;
+                (
+                    url.address().to_string(),
+                    url.options(&self.shared.options)?,
+                )
+            }
+            false => (address.to_string(), self.shared.options.clone()),
+        };
+
+        // everything up to the CONNACK has to happen within the connect timeout
+        let (conn_ack_packet, remote_address) = time::timeout(options.connect_timeout(), async {
+            let established = self.connector.connect(&address, &options).await?;
+            if let Some(remote_address) = established.remote_address {
+                info!("connected to {} at {}", address, remote_address);
+            }
+            self.stream = Some(established.transport);
+            self.write_packet(&options.connect_packet().encode())
+                .await?;
+            // nothing else is written before the CONNACK
+            self.flush().await?;
+
+            let conn_ack_bytes = self.read_packet().await?;
+            let conn_ack = ConnAck::try_from(conn_ack_bytes.as_slice())?;
+            if conn_ack.fixed_header.packet_type != ControlPacketType::ConnAck {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "expected a CONNACK",
+                ));
+            }
+            Ok((conn_ack, established.remote_address))
+        })
+        .await
+        .map_err(|_| ConnectError::Timeout)??;
+        info!("received a {:?}", conn_ack_packet);
+        if let Some(refused) = ConnectError::from_return_code(conn_ack_packet.return_code()) {
+            return Err(refused);
+        }
+        let session_present = conn_ack_packet.session_present();
+
+        self.shared.connected.store(true, Ordering::SeqCst);
+        self.shared.metrics.connected();
+        if !session_present {
+            // the reply filter has to be subscribed again before the next request
+            self.shared.requests.lock().unwrap().subscribed = false;
+        }
+        let keep_alive = options.keep_alive();
+        if !keep_alive.is_zero() {
+            let mut ping_timer = time::interval_at(Instant::now() + keep_alive, keep_alive);
+            ping_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            self.ping_timer = Some(ping_timer);
+        }
+        self.resume_session(session_present).await?;
+        self.flush_offline_queue().await?;
+        Ok(Connected {
+            session_present,
+            remote_address,
+        })
+    }
+
+    // Reads until a whole packet is buffered, only used while waiting for the CONNACK
+    async fn read_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(packet_length) = complete_packet_length(&self.read_buffer)? {
+                let received: Vec<u8> = self.read_buffer.drain(..packet_length).collect();
+                self.shared.metrics.packet_received(&received);
+                return Ok(received);
+            }
+            let read = read_some(&mut self.stream, &mut self.read_buffer).await;
+            check_read(read)?;
+        }
+    }
+
+    async fn handle_read(&mut self, read: io::Result<usize>) -> io::Result<()> {
+        check_read(read)?;
+        while let Some(packet_length) = complete_packet_length(&self.read_buffer)? {
+            let received: Vec<u8> = self.read_buffer.drain(..packet_length).collect();
+            self.handle_packet(received).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_packet(&mut self, received: Vec<u8>) -> io::Result<()> {
+        self.shared.metrics.packet_received(&received);
+        // a packet that can't be decoded is an error like any other, it closes the connection
+        match FixedHeader::try_from(received.as_slice())?.packet_type {
+            ControlPacketType::Publish => {
+                let publish_packet = PublishPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", publish_packet);
+                self.handle_publish(&publish_packet).await?;
+            }
+            ControlPacketType::PubAck => {
+                let pub_ack_packet = PubAckPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", pub_ack_packet);
+                let completed = self
+                    .shared
+                    .session
+                    .lock()
+                    .unwrap()
+                    .handle_pub_ack(pub_ack_packet.packet_id);
+                if completed.is_some() {
+                    self.release_in_flight().await?;
+                }
+            }
+            ControlPacketType::PubRec => {
+                let pub_rec_packet = PubRecPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", pub_rec_packet);
+                let pub_rel_packet = self
+                    .shared
+                    .session
+                    .lock()
+                    .unwrap()
+                    .handle_pub_rec(pub_rec_packet.packet_id);
+                if let Some(pub_rel_packet) = pub_rel_packet {
+                    self.write_packet(&pub_rel_packet.encode()).await?;
+                }
+            }
+            ControlPacketType::PubRel => {
+                let pub_rel_packet = PubRelPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", pub_rel_packet);
+                let pub_comp_packet = self
+                    .shared
+                    .session
+                    .lock()
+                    .unwrap()
+                    .handle_pub_rel(pub_rel_packet.packet_id);
+                self.write_packet(&pub_comp_packet.encode()).await?;
+            }
+            ControlPacketType::PubComp => {
+                let pub_comp_packet = PubCompPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", pub_comp_packet);
+                let completed = self
+                    .shared
+                    .session
+                    .lock()
+                    .unwrap()
+                    .handle_pub_comp(pub_comp_packet.packet_id);
+                if completed.is_some() {
+                    self.release_in_flight().await?;
+                }
+            }
+            ControlPacketType::SubAck => {
+                let sub_ack_packet = SubAckPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", sub_ack_packet);
+                let packet_id = sub_ack_packet.packet_id;
+                // after a timeout the id was released already and may be in use again
+                let completed = self
+                    .shared
+                    .pending_acks
+                    .lock()
+                    .unwrap()
+                    .complete_subscribe(packet_id, sub_ack_packet.return_codes);
+                if completed {
+                    self.shared
+                        .session
+                        .lock()
+                        .unwrap()
+                        .release_packet_id(packet_id);
+                }
+            }
+            ControlPacketType::UnsubAck => {
+                let unsub_ack_packet = UnsubAckPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", unsub_ack_packet);
+                let packet_id = unsub_ack_packet.packet_id;
+                let completed = self
+                    .shared
+                    .pending_acks
+                    .lock()
+                    .unwrap()
+                    .complete_unsubscribe(packet_id);
+                if completed {
+                    self.shared
+                        .session
+                        .lock()
+                        .unwrap()
+                        .release_packet_id(packet_id);
+                }
+            }
+            ControlPacketType::PingResp => {
+                let ping_resp_packet = PingRespPacket::try_from(received.as_slice())?;
+                info!("received a {:?}", ping_resp_packet);
+                if let Some(ping_sent_at) = self.ping_sent_at.take() {
+                    self.shared.metrics.ping_answered(ping_sent_at.elapsed());
+                }
+            }
+            // CONNECT, SUBSCRIBE, UNSUBSCRIBE, PINGREQ and DISCONNECT only go to the Server,
+            // and a second CONNACK is a protocol violation [MQTT-4.8.0-1]
+            packet_type => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected {:?} from the server", packet_type),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle_publish(&mut self, publish_packet: &PublishPacket<'_>) -> io::Result<()> {
+        let message = Message::from(publish_packet);
+        match (message.qos, publish_packet.packet_id) {
+            (QoS::AtMostOnce, _) => self.deliver(message),
+            (QoS::AtLeastOnce, Some(packet_id)) => {
+                self.deliver(message);
+                self.write_packet(&PubAckPacket::new(packet_id).encode())
+                    .await?;
+            }
+            (QoS::ExactlyOnce, Some(packet_id)) => {
+                // a redelivered message is only acknowledged, the application already has it
+                let first_time = self
+                    .shared
+                    .session
+                    .lock()
+                    .unwrap()
+                    .handle_incoming_qos2(packet_id);
+                if first_time {
+                    self.deliver(message);
+                } else {
+                    debug!("Dropping duplicate of QoS 2 message {}", packet_id);
+                }
+                self.write_packet(&PubRecPacket::new(packet_id).encode())
+                    .await?;
+            }
+            (_, None) => warn!("Received a QoS > 0 publish without a packet id!"),
+        }
+        Ok(())
+    }
+
+    // Hands the message to the matching handlers, messages nobody handles end up in the inbox
+    fn deliver(&self, message: Message) {
+        if !router::route(&self.shared.router, &message) {
+            self.shared.inbox.lock().unwrap().push_back(message);
+        }
+    }
+
+    async fn resume_session(&mut self, session_present: bool) -> io::Result<()> {
+        let (retransmissions, dropped) = {
+            let mut session = self.shared.session.lock().unwrap();
+            let outgoing_count = session.outgoing_count();
+            let retransmissions = session.resume(session_present);
+            (retransmissions, outgoing_count - session.outgoing_count())
+        };
+        // pending PUBRELs the Server no longer knows about free their slots
+        for _ in 0..dropped {
+            self.shared.in_flight_window.release();
+        }
+        for packet_bytes in retransmissions {
+            self.write_packet(&packet_bytes).await?;
+        }
+        Ok(())
+    }
+
+    // While disconnected the message is queued and sent after the next connect.
+    // Fails if the offline queue is full and configured to reject.
+    async fn publish(&mut self, message: Message, holds_slot: bool) -> io::Result<()> {
+        if self.stream.is_none() || (!holds_slot && message.qos != QoS::AtMostOnce) {
+            if holds_slot {
+                self.shared.in_flight_window.release();
+            }
+            let expiry = self.shared.options.offline_message_expiry();
+            let dropped = self
+                .shared
+                .offline_queue
+                .lock()
+                .unwrap()
+                .push(message, expiry)
+                .map_err(|error| io::Error::new(io::ErrorKind::NotConnected, error))?;
+            if let Some(dropped) = dropped {
+                warn!(
+                    "Offline queue is full, dropped a message for {}",
+                    dropped.topic
+                );
+            }
+            // the connection came up while the handle wasn't holding a slot
+            return self.flush_offline_queue().await;
+        }
+
+        self.send_message(message).await
+    }
+
+    // QoS 1 and QoS 2 messages must already hold a slot in the in-flight window
+    async fn send_message(&mut self, message: Message) -> io::Result<()> {
+        let qos = message.qos;
+        let publish_packet_bytes = match message.qos {
+            QoS::AtMostOnce => publish_packet::Builder::new()
+                .packet_flags(PublishPacketFlags::new(false, message.qos, message.retain))
+                .topic_name(&message.topic)
+                .payload(&message.payload)
+                .build()
+                .unwrap()
+                .encode(),
+            // QoS 1 and 2 messages are kept in the session until they are acknowledged
+            _ => {
+                let mut session = self.shared.session.lock().unwrap();
+                let published = session
+                    .acquire_packet_id()
+                    .map_err(|error| io::Error::new(io::ErrorKind::WouldBlock, error))
+                    .and_then(|packet_id| {
+                        let published = session.publish(packet_id, message);
+                        if published.is_err() {
+                            session.release_packet_id(packet_id);
+                        }
+                        published
+                    });
+                if published.is_err() {
+                    self.shared.in_flight_window.release();
+                }
+                published?
+            }
+        };
+        if let Err(error) = self.write_packet(&publish_packet_bytes).await {
+            let failed = io::Error::new(error.kind(), error.to_string());
+            self.connection_lost(error);
+            // a QoS 1 or 2 message is still in the session and goes out again after a reconnect,
+            // a QoS 0 message is gone
+            if qos == QoS::AtMostOnce {
+                return Err(failed);
+            }
+        }
+        Ok(())
+    }
+
+    // Sends what was published while disconnected, after the session's own retransmissions.
+    // QoS 1 and QoS 2 messages that don't fit in the in-flight window stay queued until
+    // acknowledgements free up their slots.
+    async fn flush_offline_queue(&mut self) -> io::Result<()> {
+        while self.stream.is_some() {
+            let window = &self.shared.in_flight_window;
+            let message = self
+                .shared
+                .offline_queue
+                .lock()
+                .unwrap()
+                .pop_if(|message| message.qos == QoS::AtMostOnce || window.try_acquire());
+            match message {
+                Some(message) => match self.send_message(message).await {
+                    Ok(()) => {}
+                    // whoever queued a QoS 0 message was already told it's queued
+                    Err(error) if self.stream.is_none() => {
+                        warn!("Dropped a queued message: {}", error);
+                        break;
+                    }
+                    Err(error) => return Err(error),
+                },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    async fn release_in_flight(&mut self) -> io::Result<()> {
+        self.shared.in_flight_window.release();
+        self.flush_offline_queue().await
+    }
+
+    async fn write_packet(&mut self, packet_bytes: &[u8]) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => {
+                stream.write_all(packet_bytes).await?;
+                // TLS buffers what it encrypts, and a flush waits for the io_uring thread.
+                // The packets of commands that are already waiting are flushed together.
+                match self.commands.is_empty() {
+                    true => {
+                        stream.flush().await?;
+                        self.unflushed = false;
+                    }
+                    false => self.unflushed = true,
+                }
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "not connected to a server",
+                ))
+            }
+        }
+        self.shared.metrics.packet_sent(packet_bytes);
+        // the round trip is measured from the first of several unanswered PINGREQs
+        if ControlPacketType::from(packet_bytes[0] >> 4) == ControlPacketType::PingReq
+            && self.ping_sent_at.is_none()
+        {
+            self.ping_sent_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn report_queue_sizes(&self) {
+        let queue_depth = self.shared.offline_queue.lock().unwrap().len();
+        self.shared
+            .metrics
+            .queue_sizes(self.shared.in_flight_window.in_flight(), queue_depth);
+    }
+
+    fn connection_lost(&mut self, error: io::Error) {
+        if self.stream.is_some() {
+            error!("Lost the connection to the server: {}", error);
+        }
+        self.close();
+        if self.follow_brokers && self.draining.is_none() && self.reconnecting.is_none() {
+            self.schedule_reconnect(0);
+        }
+    }
+
+    // Nobody listening for events is fine
+    fn report(&self, event: ConnectionEvent) {
+        let _ = self.shared.events.send(event);
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.unflushed = false;
+        match &mut self.stream {
+            Some(stream) => stream.flush().await,
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) {
+        self.unflushed = false;
+        if let Some(endpoint) = self.endpoint.take() {
+            self.report(ConnectionEvent::Disconnected { endpoint });
+        }
+        self.stream = None;
+        self.read_buffer.clear();
+        self.ping_timer = None;
+        self.ping_sent_at = None;
+        self.shared.connected.store(false, Ordering::SeqCst);
+
+        // SUBSCRIBE and UNSUBSCRIBE are not retransmitted, whoever waits on them learns the connection is gone
+        let abandoned_packet_ids = self.shared.pending_acks.lock().unwrap().clear();
+        let mut session = self.shared.session.lock().unwrap();
+        for packet_id in abandoned_packet_ids {
+            session.release_packet_id(packet_id);
+        }
+    }
+}
+
+// Appends whatever the socket has to the buffer. Never completes while there is no socket.
+async fn read_some(stream: &mut Option<BoxedTransport>, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    match stream {
+        Some(stream) => stream.read_buf(buffer).await,
+        None => future::pending().await,
+    }
+}
+
+async fn drain_deadline(draining: &Option<Draining>) {
+    match draining {
+        Some(draining) => time::sleep_until(draining.deadline).await,
+        None => future::pending().await,
+    }
+}
+
+async fn reconnect_deadline(reconnecting: &Option<Reconnecting>) {
+    match reconnecting {
+        Some(reconnecting) => time::sleep_until(reconnecting.at).await,
+        None => future::pending().await,
+    }
+}
+
+pub(crate) fn shutting_down() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the client is shutting down")
+}
+
+async fn next_tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+fn check_read(read: io::Result<usize>) -> io::Result<()> {
+    match read? {
+        0 => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the server closed the connection",
+        )),
+        _ => Ok(()),
+    }
+}
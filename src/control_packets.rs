@@ -19,7 +19,7 @@
 
 // 2.2.1. MQTT Control Packet type
 
-use std::fmt;
+use std::{fmt, io};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
@@ -63,9 +63,9 @@ impl From<u8> for ControlPacketType {
     }
 }
 
-impl Into<u8> for ControlPacketType {
-    fn into(self) -> u8 {
-        match self {
+impl From<ControlPacketType> for u8 {
+    fn from(val: ControlPacketType) -> Self {
+        match val {
             ControlPacketType::Connect => 1,
             ControlPacketType::ConnAck => 2,
             ControlPacketType::Publish => 3,
@@ -141,12 +141,12 @@ pub fn encode_remaining_length(mut length: usize) -> Result<Vec<u8>, EncodeError
 
     loop {
         let mut encoded_byte: u8 = (length % 128).try_into().unwrap();
-        length = length / 128;
+        length /= 128;
         if length > 0 {
-            encoded_byte = encoded_byte | 128;
+            encoded_byte |= 128;
         }
         vec.push(encoded_byte);
-        if length <= 0 {
+        if length == 0 {
             if vec.len() > 4 {
                 return Err(EncodeError);
             }
@@ -171,7 +171,7 @@ pub fn decode_remaining_length(encoded: &[u8]) -> Result<u32, DecodeError> {
     let mut value: u32 = 0;
     let mut index: usize = 0;
     loop {
-        value = value + (encoded[index] & 127) as u32 * multiplier;
+        value += (encoded[index] & 127) as u32 * multiplier;
         multiplier *= 128;
         if multiplier > MAX_REMAINING_LENGTH {
             return Err(DecodeError);
@@ -185,7 +185,9 @@ pub fn decode_remaining_length(encoded: &[u8]) -> Result<u32, DecodeError> {
 
 #[cfg(test)]
 mod remaining_length_conversion_tests {
-    use crate::control_packets::{decode_remaining_length, encode_remaining_length};
+    use crate::control_packets::{
+        complete_packet_length, decode_remaining_length, encode_remaining_length,
+    };
 
     #[test]
     fn decode_remaining_length_test() {
@@ -227,6 +229,18 @@ mod remaining_length_conversion_tests {
         let length_bytes = encode_remaining_length(length);
         assert!(length_bytes.is_err());
     }
+
+    #[test]
+    fn complete_packet_length_test() {
+        // a PUBACK followed by the first byte of a PINGRESP
+        let received: [u8; 5] = [0x40, 0x02, 0x00, 0x07, 0xd0];
+        assert_eq!(complete_packet_length(&received).unwrap(), Some(4));
+        assert_eq!(complete_packet_length(&received[4..]).unwrap(), None);
+        assert_eq!(complete_packet_length(&received[..3]).unwrap(), None);
+        // 321 bytes of remaining length, only the header arrived so far
+        assert_eq!(complete_packet_length(&[0x30, 193, 2]).unwrap(), None);
+        assert!(complete_packet_length(&[0x30, 255, 255, 255, 255, 1]).is_err());
+    }
 }
 
 pub(crate) trait Encodable {
@@ -240,16 +254,38 @@ pub struct FixedHeader {
     pub remaining_length: usize,
 }
 
-impl From<&[u8]> for FixedHeader {
-    fn from(bytes: &[u8]) -> Self {
-        FixedHeader {
+impl FixedHeader {
+    // Splits a whole packet into its fixed header and the bytes the remaining length covers.
+    // Fails with InvalidData if the packet is shorter than its remaining length says.
+    pub fn split(bytes: &[u8]) -> io::Result<(FixedHeader, &[u8])> {
+        let packet_length = complete_packet_length(bytes)?.ok_or_else(|| malformed("packet"))?;
+        // complete_packet_length checked the remaining length bytes
+        let remaining_length = decode_remaining_length(&bytes[1..]).unwrap() as usize;
+        let fixed_header = FixedHeader {
             packet_type: ControlPacketType::from((bytes[0] >> 4) & 0x0f),
-            packet_flags: bytes[0] & 0xf0,
-            remaining_length: decode_remaining_length(&bytes[1..]).unwrap_or(0) as usize,
-        }
+            packet_flags: bytes[0] & 0x0f,
+            remaining_length,
+        };
+        Ok((
+            fixed_header,
+            &bytes[packet_length - remaining_length..packet_length],
+        ))
+    }
+}
+
+impl TryFrom<&[u8]> for FixedHeader {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        FixedHeader::split(bytes).map(|(fixed_header, _)| fixed_header)
     }
 }
 
+// A packet that's too short for its type or otherwise can't be decoded
+pub fn malformed(packet: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed {}", packet))
+}
+
 impl Encodable for FixedHeader {
     fn encode(&self) -> Vec<u8> {
         let mut vec: Vec<u8> = Vec::new();
@@ -262,6 +298,35 @@ impl Encodable for FixedHeader {
     }
 }
 
+// Returns the length of the control packet at the start of the buffer, once all of it has been
+// received. Used to split bytes read from an async stream into packets.
+pub fn complete_packet_length(buffer: &[u8]) -> io::Result<Option<usize>> {
+    let mut header_length = 1;
+    loop {
+        match buffer.get(header_length) {
+            None => return Ok(None),
+            Some(length_byte) if length_byte & 128 == 0 => break,
+            Some(_) => {}
+        }
+        header_length += 1;
+        if header_length > 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                DecodeError.to_string(),
+            ));
+        }
+    }
+    header_length += 1;
+
+    let remaining_length = decode_remaining_length(&buffer[1..header_length])
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))?;
+    let packet_length = header_length + remaining_length as usize;
+    if buffer.len() < packet_length {
+        return Ok(None);
+    }
+    Ok(Some(packet_length))
+}
+
 // 2.3. Variable header
 // 2.3.1 Packet identifier
 //  ----------------------------------------------------------------------------------------
@@ -290,10 +355,15 @@ impl Encodable for FixedHeader {
 // | UNSUBSCRIBE    | Required |
 //  ---------------------------
 
+// The packet identifier at the start of a variable header, None if it's cut short
+pub fn decode_packet_id(variable_header: &[u8]) -> Option<u16> {
+    variable_header.get(..2).map(as_u16_be)
+}
+
 pub fn as_u16_be(array: &[u8]) -> u16 {
-    ((array[0] as u16) << 8) + ((array[1] as u16) << 0)
+    ((array[0] as u16) << 8) + (array[1] as u16)
 }
 
 pub fn as_u16_le(array: &[u8]) -> u16 {
-    ((array[0] as u16) << 0) + ((array[1] as u16) << 8)
+    (array[0] as u16) + ((array[1] as u16) << 8)
 }
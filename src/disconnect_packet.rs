@@ -1,7 +1,4 @@
-use crate::{
-    control_packets::{ControlPacketFlags, Encodable, FixedHeader},
-    ControlPacketType,
-};
+use crate::control_packets::{ControlPacketFlags, ControlPacketType, Encodable, FixedHeader};
 
 pub(crate) struct DisconnectPacket {
     pub fixed_header: FixedHeader,
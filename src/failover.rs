@@ -0,0 +1,83 @@
+// Which of several brokers to try first. The brokers of a highly available pair or cluster are
+// listed in the options, ClientHandle::connect_to_brokers tries them in the order the policy
+// gives until one accepts the connection, and the connection task does the same whenever it
+// reconnects after losing that connection.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FailoverPolicy {
+    // Always starts with the first broker, so the client goes back to the primary whenever it
+    // is available again
+    #[default]
+    PreferPrimary,
+    // Starts with the broker after the one connected to last, spreading reconnects over the list
+    RoundRobin,
+}
+
+#[derive(Debug)]
+pub(crate) struct Failover {
+    policy: FailoverPolicy,
+    brokers: usize,
+    // index of the broker connected to last
+    last: Option<usize>,
+}
+
+impl Failover {
+    pub fn new(policy: FailoverPolicy, brokers: usize) -> Self {
+        Failover {
+            policy,
+            brokers,
+            last: None,
+        }
+    }
+
+    // The indices of the brokers in the order they're tried
+    pub fn order(&self) -> Vec<usize> {
+        if self.brokers == 0 {
+            return Vec::new();
+        }
+        let first = match (self.policy, self.last) {
+            (FailoverPolicy::RoundRobin, Some(last)) => (last + 1) % self.brokers,
+            _ => 0,
+        };
+        (0..self.brokers)
+            .map(|offset| (first + offset) % self.brokers)
+            .collect()
+    }
+
+    pub fn connected(&mut self, index: usize) {
+        self.last = Some(index);
+    }
+}
+
+#[cfg(test)]
+mod failover_tests {
+    use super::{Failover, FailoverPolicy};
+
+    #[test]
+    fn prefer_primary_test() {
+        let mut failover = Failover::new(FailoverPolicy::PreferPrimary, 3);
+        assert_eq!(failover.order(), vec![0, 1, 2]);
+        failover.connected(1);
+        assert_eq!(failover.order(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn round_robin_test() {
+        let mut failover = Failover::new(FailoverPolicy::RoundRobin, 3);
+        assert_eq!(failover.order(), vec![0, 1, 2]);
+        failover.connected(0);
+        assert_eq!(failover.order(), vec![1, 2, 0]);
+        failover.connected(2);
+        assert_eq!(failover.order(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn no_brokers_test() {
+        let mut failover = Failover::new(FailoverPolicy::RoundRobin, 0);
+        assert!(failover.order().is_empty());
+        failover.connected(0);
+        assert!(Failover::new(FailoverPolicy::PreferPrimary, 0)
+            .order()
+            .is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::{
+    net::{self, TcpStream},
+    time::{self, Instant},
+};
+use tracing::debug;
+
+// Happy Eyeballs (RFC 8305): every address a host name resolves to is tried, IPv6 and IPv4
+// alternating and IPv6 first. A new attempt starts whenever the previous one failed or didn't
+// succeed within the connection attempt delay, earlier attempts keep running, and the first
+// connection that's established wins. So a broker whose IPv6 address is unreachable costs a
+// delay instead of an OS connect timeout. The connect timeout of the options bounds the race.
+
+// The delay RFC 8305 section 5 recommends
+pub const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+// Resolves "host:port" and races its addresses, returns the connection and the address it's to
+pub(crate) async fn connect(
+    address: &str,
+    attempt_delay: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let addresses = interleave(net::lookup_host(address).await?.collect());
+    race(addresses, attempt_delay).await
+}
+
+// RFC 8305 section 4, IPv6 first and then alternating families
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (ipv6, ipv4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut ipv6, mut ipv4) = (ipv6.into_iter(), ipv4.into_iter());
+    let mut interleaved = Vec::new();
+    loop {
+        match (ipv6.next(), ipv4.next()) {
+            (None, None) => return interleaved,
+            (first, second) => interleaved.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+// Tries the addresses in order, fails with the error of the last attempt if none succeeds
+pub(crate) async fn race(
+    addresses: Vec<SocketAddr>,
+    attempt_delay: Duration,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut pending = addresses.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    let next_attempt = time::sleep(Duration::ZERO);
+    tokio::pin!(next_attempt);
+    loop {
+        if attempts.is_empty() && pending.len() == 0 {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+            }));
+        }
+        tokio::select! {
+            _ = &mut next_attempt, if pending.len() > 0 => {
+                let address = pending.next().unwrap();
+                attempts.push(async move { (address, TcpStream::connect(address).await) });
+                next_attempt.as_mut().reset(Instant::now() + attempt_delay);
+            }
+            Some((address, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, address)),
+                Err(error) => {
+                    debug!("Failed to connect to {}: {}", address, error);
+                    last_error = Some(error);
+                    // no reason to wait for the next attempt
+                    next_attempt.as_mut().reset(Instant::now());
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod happy_eyeballs_tests {
+    use std::{
+        io,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use tokio::net::TcpListener;
+
+    use super::{connect, interleave, race};
+
+    async fn listen(address: &str) -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind(address).await.unwrap();
+        let address = listener.local_addr().unwrap();
+        (listener, address)
+    }
+
+    // An address on the family's loopback nobody listens on
+    async fn refusing(address: &str) -> SocketAddr {
+        listen(address).await.1
+    }
+
+    #[test]
+    fn interleave_test() {
+        let addresses: Vec<SocketAddr> =
+            ["1.1.1.1:1", "2.2.2.2:1", "[::1]:1", "[::2]:1", "[::3]:1"]
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect();
+        let interleaved: Vec<String> = interleave(addresses)
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect();
+        assert_eq!(
+            interleaved,
+            ["[::1]:1", "1.1.1.1:1", "[::2]:1", "2.2.2.2:1", "[::3]:1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn ipv6_first_test() {
+        let (_ipv6, ipv6_address) = listen("[::1]:0").await;
+        let (_ipv4, ipv4_address) = listen("127.0.0.1:0").await;
+        let (stream, winner) = race(vec![ipv6_address, ipv4_address], Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(winner, ipv6_address);
+        assert_eq!(stream.peer_addr().unwrap(), ipv6_address);
+    }
+
+    #[tokio::test]
+    async fn fallback_test() {
+        let ipv6_address = refusing("[::1]:0").await;
+        let (_ipv4, ipv4_address) = listen("127.0.0.1:0").await;
+        let start = Instant::now();
+        // the refused attempt starts the next one right away, long before the delay is over
+        let (_, winner) = race(vec![ipv6_address, ipv4_address], Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(winner, ipv4_address);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn all_refused_test() {
+        let addresses = vec![refusing("[::1]:0").await, refusing("127.0.0.1:0").await];
+        let error = race(addresses, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            race(Vec::new(), Duration::from_millis(10))
+                .await
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn connect_test() {
+        let (_ipv4, ipv4_address) = listen("127.0.0.1:0").await;
+        let (_, winner) = connect(
+            &format!("localhost:{}", ipv4_address.port()),
+            Duration::from_millis(250),
+        )
+        .await
+        .unwrap();
+        assert_eq!(winner, ipv4_address);
+    }
+}
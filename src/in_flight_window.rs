@@ -0,0 +1,163 @@
+use std::{error::Error, fmt, sync::Mutex};
+
+use tokio::sync::Semaphore;
+
+// Limits how many QoS 1 and QoS 2 publishes can wait for their acknowledgement at the same time.
+// Some brokers disconnect clients that go over their limit.
+//
+// Every free slot is a permit of the semaphore. A slot is taken before a PUBLISH is sent and
+// given back when the flow completes with PUBACK or PUBCOMP.
+
+#[derive(Debug, Clone)]
+pub struct WindowClosed;
+
+impl fmt::Display for WindowClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The in-flight window is closed")
+    }
+}
+
+impl Error for WindowClosed {}
+
+#[derive(Debug, Default)]
+struct WindowState {
+    in_flight: usize,
+    // slots that are in use but don't exist, because restored messages didn't fit.
+    // They are paid back by releases instead of turning into permits.
+    debt: usize,
+}
+
+#[derive(Debug)]
+pub struct InFlightWindow {
+    permits: Semaphore,
+    state: Mutex<WindowState>,
+}
+
+impl InFlightWindow {
+    pub fn new(limit: usize) -> Self {
+        InFlightWindow {
+            permits: Semaphore::new(limit),
+            state: Mutex::new(WindowState::default()),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight
+    }
+
+    // Waits until a slot is free and takes it.
+    // Fails once the window is closed, including for callers that are already waiting.
+    pub async fn acquire(&self) -> Result<(), WindowClosed> {
+        self.permits
+            .acquire()
+            .await
+            .map_err(|_| WindowClosed)?
+            .forget();
+        self.state.lock().unwrap().in_flight += 1;
+        Ok(())
+    }
+
+    // Takes a slot if one is free right now
+    pub fn try_acquire(&self) -> bool {
+        match self.permits.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                self.state.lock().unwrap().in_flight += 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Takes slots for messages that are already in flight, e.g. restored from a SessionStore,
+    // even if that goes over the limit
+    pub fn occupy(&self, count: usize) {
+        let forgotten = self.permits.forget_permits(count);
+        let mut state = self.state.lock().unwrap();
+        state.in_flight += count;
+        state.debt += count - forgotten;
+    }
+
+    // Wakes every waiting acquire with an error, called when the connection task stops
+    pub fn close(&self) {
+        self.permits.close();
+    }
+
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight == 0 {
+            return;
+        }
+        state.in_flight -= 1;
+        if state.debt > 0 {
+            state.debt -= 1;
+        } else {
+            self.permits.add_permits(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod in_flight_window_tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::InFlightWindow;
+
+    #[tokio::test]
+    async fn acquire_waits_for_release_test() {
+        let window = Arc::new(InFlightWindow::new(2));
+        window.acquire().await.unwrap();
+        window.acquire().await.unwrap();
+        assert_eq!(window.in_flight(), 2);
+        assert!(!window.try_acquire());
+
+        let waiting_window = Arc::clone(&window);
+        let waiting = tokio::spawn(async move { waiting_window.acquire().await.unwrap() });
+        assert!(timeout(Duration::from_millis(20), async {
+            while !waiting.is_finished() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .is_err());
+
+        window.release();
+        timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(window.in_flight(), 2);
+    }
+
+    #[tokio::test]
+    async fn close_wakes_waiters_test() {
+        let window = Arc::new(InFlightWindow::new(1));
+        window.acquire().await.unwrap();
+
+        let waiting_window = Arc::clone(&window);
+        let waiting = tokio::spawn(async move { waiting_window.acquire().await });
+        tokio::task::yield_now().await;
+        window.close();
+        assert!(timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_err());
+        assert!(window.acquire().await.is_err());
+        assert!(!window.try_acquire());
+    }
+
+    #[test]
+    fn occupy_over_limit_test() {
+        let window = InFlightWindow::new(2);
+        window.occupy(3);
+        assert_eq!(window.in_flight(), 3);
+        window.release();
+        assert!(!window.try_acquire());
+        window.release();
+        assert!(window.try_acquire());
+        assert!(!window.try_acquire());
+    }
+}
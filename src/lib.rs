@@ -0,0 +1,34 @@
+pub mod blocking_client;
+pub mod broker_url;
+pub mod client_handle;
+pub mod client_metrics;
+pub mod conn_ack_packet;
+pub mod connect_packet;
+mod connection;
+pub mod control_packets;
+pub mod disconnect_packet;
+pub mod failover;
+pub mod happy_eyeballs;
+pub mod in_flight_window;
+pub mod mqtt_options;
+pub mod offline_queue;
+pub mod packet_id;
+pub mod payload;
+pub mod pending_acks;
+pub mod ping_packets;
+pub mod proxy;
+pub mod pub_ack_packets;
+pub mod publish_packet;
+pub mod request;
+pub mod router;
+pub mod session_state;
+pub mod session_store;
+pub mod sub_ack_packet;
+pub mod subscribe_packet;
+pub mod tls;
+pub mod transport;
+pub mod unsub_ack_packet;
+pub mod unsubscribe_packet;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod websocket;
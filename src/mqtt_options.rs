@@ -0,0 +1,722 @@
+use std::{error::Error, fmt, time::Duration};
+
+use crate::{
+    connect_packet::{self, ConnectPacket, QoS},
+    failover::FailoverPolicy,
+    happy_eyeballs,
+    offline_queue::OverflowPolicy,
+    proxy::{self, Proxy},
+    tls::TlsOptions,
+    websocket::WebSocketOptions,
+};
+
+// Everything the client needs to open a session: the content of the CONNECT packet
+// plus the client side timeouts. Options are validated when they are built, so that
+// a misconfiguration is reported before a connection is even attempted.
+
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(60);
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_OFFLINE_QUEUE_CAPACITY: usize = 100;
+pub const DEFAULT_MAX_IN_FLIGHT: u16 = 100;
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// 1.5.3 UTF-8 encoded strings are prefixed with a two byte length
+const MAX_STRING_LENGTH: usize = u16::MAX as usize;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MqttOptionsError {
+    // An empty client id is only accepted together with a clean session [MQTT-3.1.3-7]
+    EmptyClientIdWithoutCleanSession,
+    // The keep alive is sent as a 16 bit number of seconds
+    KeepAliveTooLong,
+    // A keep alive under a second would be sent as 0, which turns it off for the Server
+    // while the client still pings at that interval
+    KeepAliveTooShort,
+    // A password can't be sent without a user name [MQTT-3.1.2-22]
+    PasswordWithoutUserName,
+    // The will topic is a topic name, not a filter, so it can't be empty or contain wildcards
+    InvalidWillTopic,
+    // One of the strings doesn't fit in its two byte length prefix
+    StringTooLong(&'static str),
+    // Client side timeouts and the reconnect delay must be non-zero
+    ZeroTimeout(&'static str),
+    // At least one QoS 1 or QoS 2 publish has to be allowed in flight
+    ZeroMaxInFlight,
+    // SOCKS5 sends the proxy's user name and password with a one byte length
+    ProxyCredentialsTooLong,
+}
+
+impl fmt::Display for MqttOptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MqttOptionsError::EmptyClientIdWithoutCleanSession => {
+                write!(f, "An empty client id requires a clean session")
+            }
+            MqttOptionsError::KeepAliveTooLong => {
+                write!(f, "Keep alive can't be longer than 65535 seconds")
+            }
+            MqttOptionsError::KeepAliveTooShort => {
+                write!(f, "Keep alive must be zero or at least one second")
+            }
+            MqttOptionsError::PasswordWithoutUserName => {
+                write!(f, "A password requires a user name")
+            }
+            MqttOptionsError::InvalidWillTopic => {
+                write!(
+                    f,
+                    "The will topic must be a non-empty topic name without wildcards"
+                )
+            }
+            MqttOptionsError::StringTooLong(field) => {
+                write!(f, "The {} is longer than 65535 bytes", field)
+            }
+            MqttOptionsError::ZeroTimeout(field) => write!(f, "The {} must be non-zero", field),
+            MqttOptionsError::ZeroMaxInFlight => write!(f, "The in-flight window must be non-zero"),
+            MqttOptionsError::ProxyCredentialsTooLong => {
+                write!(f, "SOCKS5 proxy credentials can't be longer than 255 bytes")
+            }
+        }
+    }
+}
+
+impl Error for MqttOptionsError {}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct LastWill {
+    pub topic: String,
+    pub message: String,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct MqttOptions {
+    client_id: String,
+    keep_alive: Duration,
+    clean_session: bool,
+    user_name: Option<String>,
+    password: Option<String>,
+    last_will: Option<LastWill>,
+    connect_timeout: Duration,
+    ack_timeout: Duration,
+    offline_queue_capacity: usize,
+    offline_message_expiry: Option<Duration>,
+    overflow_policy: OverflowPolicy,
+    max_in_flight: u16,
+    proxy: Option<Proxy>,
+    tls: Option<TlsOptions>,
+    websocket: Option<WebSocketOptions>,
+    brokers: Vec<String>,
+    failover_policy: FailoverPolicy,
+    connection_attempt_delay: Duration,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+}
+
+impl MqttOptions {
+    // Options with the defaults for everything but the client id
+    pub fn new(client_id: &str) -> Result<MqttOptions, MqttOptionsError> {
+        Builder::new().client_id(client_id).build()
+    }
+
+    // A builder that starts out with these options
+    pub fn to_builder(&self) -> Builder {
+        Builder {
+            client_id: self.client_id.clone(),
+            keep_alive: self.keep_alive,
+            clean_session: self.clean_session,
+            user_name: self.user_name.clone(),
+            password: self.password.clone(),
+            last_will: self.last_will.clone(),
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
+            offline_queue_capacity: self.offline_queue_capacity,
+            offline_message_expiry: self.offline_message_expiry,
+            overflow_policy: self.overflow_policy,
+            max_in_flight: self.max_in_flight,
+            proxy: self.proxy.clone(),
+            tls: self.tls.clone(),
+            websocket: self.websocket.clone(),
+            brokers: self.brokers.clone(),
+            failover_policy: self.failover_policy,
+            connection_attempt_delay: self.connection_attempt_delay,
+            reconnect_delay: self.reconnect_delay,
+            max_reconnect_delay: self.max_reconnect_delay,
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    pub fn clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn user_name(&self) -> Option<&str> {
+        self.user_name.as_deref()
+    }
+
+    pub fn last_will(&self) -> Option<&LastWill> {
+        self.last_will.as_ref()
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn ack_timeout(&self) -> Duration {
+        self.ack_timeout
+    }
+
+    pub fn offline_queue_capacity(&self) -> usize {
+        self.offline_queue_capacity
+    }
+
+    pub fn offline_message_expiry(&self) -> Option<Duration> {
+        self.offline_message_expiry
+    }
+
+    pub fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy
+    }
+
+    pub fn max_in_flight(&self) -> u16 {
+        self.max_in_flight
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn tls(&self) -> Option<&TlsOptions> {
+        self.tls.as_ref()
+    }
+
+    pub fn websocket(&self) -> Option<&WebSocketOptions> {
+        self.websocket.as_ref()
+    }
+
+    pub fn brokers(&self) -> &[String] {
+        &self.brokers
+    }
+
+    pub fn failover_policy(&self) -> FailoverPolicy {
+        self.failover_policy
+    }
+
+    pub fn connection_attempt_delay(&self) -> Duration {
+        self.connection_attempt_delay
+    }
+
+    pub fn reconnect_delay(&self) -> Duration {
+        self.reconnect_delay
+    }
+
+    pub fn max_reconnect_delay(&self) -> Duration {
+        self.max_reconnect_delay
+    }
+
+    pub fn connect_packet(&self) -> ConnectPacket {
+        let mut builder = connect_packet::Builder::new();
+        builder
+            .client_id(&self.client_id)
+            .keep_alive_interval(self.keep_alive);
+        if self.clean_session {
+            builder.clean_session();
+        }
+        if let Some(user_name) = &self.user_name {
+            builder.user_name(user_name.clone());
+        }
+        if let Some(password) = &self.password {
+            builder.password(password.clone());
+        }
+        if let Some(last_will) = &self.last_will {
+            builder
+                .will_topic(&last_will.topic)
+                .will_message(&last_will.message)
+                .will_qos(last_will.qos.into());
+            if last_will.retain {
+                builder.will_retain();
+            }
+        }
+        // the options were validated when they were built, so this can't fail
+        builder.build().unwrap()
+    }
+}
+
+pub struct Builder {
+    client_id: String,
+    keep_alive: Duration,
+    clean_session: bool,
+    user_name: Option<String>,
+    password: Option<String>,
+    last_will: Option<LastWill>,
+    connect_timeout: Duration,
+    ack_timeout: Duration,
+    offline_queue_capacity: usize,
+    offline_message_expiry: Option<Duration>,
+    overflow_policy: OverflowPolicy,
+    max_in_flight: u16,
+    proxy: Option<Proxy>,
+    tls: Option<TlsOptions>,
+    websocket: Option<WebSocketOptions>,
+    brokers: Vec<String>,
+    failover_policy: FailoverPolicy,
+    connection_attempt_delay: Duration,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder {
+            client_id: String::new(),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            clean_session: false,
+            user_name: None,
+            password: None,
+            last_will: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            offline_queue_capacity: DEFAULT_OFFLINE_QUEUE_CAPACITY,
+            offline_message_expiry: None,
+            overflow_policy: OverflowPolicy::default(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            proxy: None,
+            tls: None,
+            websocket: None,
+            brokers: Vec::new(),
+            failover_policy: FailoverPolicy::default(),
+            connection_attempt_delay: happy_eyeballs::DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            max_reconnect_delay: DEFAULT_MAX_RECONNECT_DELAY,
+        }
+    }
+
+    pub fn client_id(&mut self, client_id: &str) -> &mut Self {
+        self.client_id = client_id.to_string();
+        self
+    }
+
+    pub fn keep_alive(&mut self, keep_alive: Duration) -> &mut Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    pub fn clean_session(&mut self, clean_session: bool) -> &mut Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    pub fn user_name(&mut self, user_name: &str) -> &mut Self {
+        self.user_name = Some(user_name.to_string());
+        self
+    }
+
+    pub fn password(&mut self, password: &str) -> &mut Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    pub fn last_will(&mut self, last_will: LastWill) -> &mut Self {
+        self.last_will = Some(last_will);
+        self
+    }
+
+    pub fn connect_timeout(&mut self, connect_timeout: Duration) -> &mut Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn ack_timeout(&mut self, ack_timeout: Duration) -> &mut Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    // How many messages published while disconnected are kept until the next connect
+    pub fn offline_queue_capacity(&mut self, offline_queue_capacity: usize) -> &mut Self {
+        self.offline_queue_capacity = offline_queue_capacity;
+        self
+    }
+
+    // Messages published while disconnected are discarded if they can't be sent within the expiry
+    pub fn offline_message_expiry(&mut self, offline_message_expiry: Duration) -> &mut Self {
+        self.offline_message_expiry = Some(offline_message_expiry);
+        self
+    }
+
+    pub fn overflow_policy(&mut self, overflow_policy: OverflowPolicy) -> &mut Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    // How many QoS 1 and QoS 2 publishes may wait for their acknowledgement at the same time
+    pub fn max_in_flight(&mut self, max_in_flight: u16) -> &mut Self {
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    // Reaches the broker through a SOCKS5 or HTTP CONNECT proxy, TLS and WebSockets run through
+    // the tunnel
+    pub fn proxy(&mut self, proxy: Proxy) -> &mut Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    // Connects over TLS instead of plain TCP
+    pub fn tls(&mut self, tls: TlsOptions) -> &mut Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Tunnels the connection through a WebSocket, over TLS as well if that's set
+    pub fn websocket(&mut self, websocket: WebSocketOptions) -> &mut Self {
+        self.websocket = Some(websocket);
+        self
+    }
+
+    // Adds a broker to the ones connect_to_brokers tries, as a broker URL or "host:port".
+    // The first one added is the primary.
+    pub fn broker(&mut self, address: &str) -> &mut Self {
+        self.brokers.push(address.to_string());
+        self
+    }
+
+    pub fn failover_policy(&mut self, failover_policy: FailoverPolicy) -> &mut Self {
+        self.failover_policy = failover_policy;
+        self
+    }
+
+    // How long to wait for a connection to one of the broker's addresses before the next address
+    // is tried alongside it
+    pub fn connection_attempt_delay(&mut self, connection_attempt_delay: Duration) -> &mut Self {
+        self.connection_attempt_delay = connection_attempt_delay;
+        self
+    }
+
+    // How long to wait before reconnecting to the brokers after the connection was lost.
+    // The delay doubles with every failed attempt, up to max_reconnect_delay.
+    pub fn reconnect_delay(&mut self, reconnect_delay: Duration) -> &mut Self {
+        self.reconnect_delay = reconnect_delay;
+        self
+    }
+
+    pub fn max_reconnect_delay(&mut self, max_reconnect_delay: Duration) -> &mut Self {
+        self.max_reconnect_delay = max_reconnect_delay;
+        self
+    }
+
+    // Sets or clears both, for broker URLs whose scheme picks the transport
+    pub(crate) fn transport(
+        &mut self,
+        tls: Option<TlsOptions>,
+        websocket: Option<WebSocketOptions>,
+    ) -> &mut Self {
+        self.tls = tls;
+        self.websocket = websocket;
+        self
+    }
+
+    pub fn build(&mut self) -> Result<MqttOptions, MqttOptionsError> {
+        self.validate()?;
+        Ok(MqttOptions {
+            client_id: self.client_id.clone(),
+            keep_alive: self.keep_alive,
+            clean_session: self.clean_session,
+            user_name: self.user_name.clone(),
+            password: self.password.clone(),
+            last_will: self.last_will.clone(),
+            connect_timeout: self.connect_timeout,
+            ack_timeout: self.ack_timeout,
+            offline_queue_capacity: self.offline_queue_capacity,
+            offline_message_expiry: self.offline_message_expiry,
+            overflow_policy: self.overflow_policy,
+            max_in_flight: self.max_in_flight,
+            proxy: self.proxy.clone(),
+            tls: self.tls.clone(),
+            websocket: self.websocket.clone(),
+            brokers: self.brokers.clone(),
+            failover_policy: self.failover_policy,
+            connection_attempt_delay: self.connection_attempt_delay,
+            reconnect_delay: self.reconnect_delay,
+            max_reconnect_delay: self.max_reconnect_delay,
+        })
+    }
+
+    fn validate(&self) -> Result<(), MqttOptionsError> {
+        if self.client_id.is_empty() && !self.clean_session {
+            return Err(MqttOptionsError::EmptyClientIdWithoutCleanSession);
+        }
+        if self.keep_alive.as_secs() > u16::MAX as u64 {
+            return Err(MqttOptionsError::KeepAliveTooLong);
+        }
+        if !self.keep_alive.is_zero() && self.keep_alive < Duration::from_secs(1) {
+            return Err(MqttOptionsError::KeepAliveTooShort);
+        }
+        if self.password.is_some() && self.user_name.is_none() {
+            return Err(MqttOptionsError::PasswordWithoutUserName);
+        }
+        if self.connect_timeout.is_zero() {
+            return Err(MqttOptionsError::ZeroTimeout("connect timeout"));
+        }
+        if self.ack_timeout.is_zero() {
+            return Err(MqttOptionsError::ZeroTimeout("ack timeout"));
+        }
+        if self.reconnect_delay.is_zero() {
+            return Err(MqttOptionsError::ZeroTimeout("reconnect delay"));
+        }
+        if self.max_in_flight == 0 {
+            return Err(MqttOptionsError::ZeroMaxInFlight);
+        }
+        if let Some(Proxy::Socks5 {
+            credentials: Some(credentials),
+            ..
+        }) = &self.proxy
+        {
+            if credentials.user_name.len() > proxy::MAX_SOCKS5_CREDENTIAL_LENGTH
+                || credentials.password.len() > proxy::MAX_SOCKS5_CREDENTIAL_LENGTH
+            {
+                return Err(MqttOptionsError::ProxyCredentialsTooLong);
+            }
+        }
+
+        let mut strings = vec![
+            ("client id", Some(&self.client_id)),
+            ("user name", self.user_name.as_ref()),
+            ("password", self.password.as_ref()),
+        ];
+        if let Some(last_will) = &self.last_will {
+            if last_will.topic.is_empty() || last_will.topic.contains(['+', '#']) {
+                return Err(MqttOptionsError::InvalidWillTopic);
+            }
+            strings.push(("will topic", Some(&last_will.topic)));
+            strings.push(("will message", Some(&last_will.message)));
+        }
+        for (field, value) in strings {
+            if value.is_some_and(|value| value.len() > MAX_STRING_LENGTH) {
+                return Err(MqttOptionsError::StringTooLong(field));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod mqtt_options_tests {
+    use std::time::Duration;
+
+    use crate::{
+        connect_packet::QoS,
+        failover::FailoverPolicy,
+        offline_queue::OverflowPolicy,
+        proxy::{Credentials, Proxy},
+    };
+
+    use super::{Builder, LastWill, MqttOptions, MqttOptionsError};
+
+    fn last_will(topic: &str) -> LastWill {
+        LastWill {
+            topic: topic.to_string(),
+            message: String::from("offline"),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn defaults_test() {
+        let options = MqttOptions::new("mqutekitty").unwrap();
+        assert_eq!(options.client_id(), "mqutekitty");
+        assert_eq!(options.keep_alive(), Duration::from_secs(60));
+        assert!(!options.clean_session());
+        assert!(options.user_name().is_none());
+        assert!(options.last_will().is_none());
+        assert_eq!(options.offline_queue_capacity(), 100);
+        assert!(options.offline_message_expiry().is_none());
+        assert_eq!(options.overflow_policy(), OverflowPolicy::DropOldest);
+        assert!(options.proxy().is_none());
+        assert!(options.tls().is_none());
+        assert!(options.websocket().is_none());
+        assert!(options.brokers().is_empty());
+        assert_eq!(options.failover_policy(), FailoverPolicy::PreferPrimary);
+        assert_eq!(
+            options.connection_attempt_delay(),
+            Duration::from_millis(250)
+        );
+    }
+
+    #[test]
+    fn connect_packet_test() {
+        let options = Builder::new()
+            .client_id("mqutekitty")
+            .clean_session(true)
+            .keep_alive(Duration::from_secs(30))
+            .user_name("user")
+            .password("secret")
+            .last_will(last_will("status/mqutekitty"))
+            .build()
+            .unwrap();
+
+        let connect_packet = options.connect_packet();
+
+        assert_eq!(connect_packet.connect_flags, 0b1110_1110.into());
+        assert_eq!(connect_packet.keep_alive, 30);
+        assert_eq!(connect_packet.client_id, "mqutekitty");
+        assert_eq!(connect_packet.user_name.as_deref(), Some("user"));
+        assert_eq!(connect_packet.password.as_deref(), Some("secret"));
+        assert_eq!(
+            connect_packet.will_topic.as_deref(),
+            Some("status/mqutekitty")
+        );
+        assert_eq!(connect_packet.will_message.as_deref(), Some("offline"));
+    }
+
+    #[test]
+    fn empty_client_id_test() {
+        assert_eq!(
+            Builder::new().build().unwrap_err(),
+            MqttOptionsError::EmptyClientIdWithoutCleanSession
+        );
+        assert!(Builder::new().clean_session(true).build().is_ok());
+    }
+
+    #[test]
+    fn password_without_user_name_test() {
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .password("secret")
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::PasswordWithoutUserName
+        );
+    }
+
+    #[test]
+    fn keep_alive_too_long_test() {
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .keep_alive(Duration::from_secs(65536))
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::KeepAliveTooLong
+        );
+    }
+
+    #[test]
+    fn keep_alive_too_short_test() {
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .keep_alive(Duration::from_millis(500))
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::KeepAliveTooShort
+        );
+        // zero turns the keep alive off
+        assert!(Builder::new()
+            .client_id("c")
+            .keep_alive(Duration::ZERO)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_will_topic_test() {
+        for topic in ["", "status/+", "status/#"] {
+            assert_eq!(
+                Builder::new()
+                    .client_id("c")
+                    .last_will(last_will(topic))
+                    .build()
+                    .unwrap_err(),
+                MqttOptionsError::InvalidWillTopic
+            );
+        }
+    }
+
+    #[test]
+    fn string_too_long_test() {
+        let user_name = "u".repeat(65536);
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .user_name(&user_name)
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::StringTooLong("user name")
+        );
+    }
+
+    #[test]
+    fn zero_timeout_test() {
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .ack_timeout(Duration::ZERO)
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::ZeroTimeout("ack timeout")
+        );
+        // it doubles from there, zero would reconnect in a busy loop
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .reconnect_delay(Duration::ZERO)
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::ZeroTimeout("reconnect delay")
+        );
+    }
+
+    #[test]
+    fn zero_max_in_flight_test() {
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .max_in_flight(0)
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::ZeroMaxInFlight
+        );
+    }
+
+    #[test]
+    fn proxy_credentials_too_long_test() {
+        let proxy = |user_name: &str| Proxy::Socks5 {
+            address: "proxy.local:1080".to_string(),
+            credentials: Some(Credentials {
+                user_name: user_name.to_string(),
+                password: "meow".to_string(),
+            }),
+        };
+        assert!(Builder::new()
+            .client_id("c")
+            .proxy(proxy(&"k".repeat(255)))
+            .build()
+            .is_ok());
+        assert_eq!(
+            Builder::new()
+                .client_id("c")
+                .proxy(proxy(&"k".repeat(256)))
+                .build()
+                .unwrap_err(),
+            MqttOptionsError::ProxyCredentialsTooLong
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::publish_packet::Message;
+
+// Messages published while there is no connection. They are kept in publish order and sent
+// once the client is connected again, unless they expired in the meantime.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OverflowPolicy {
+    // make room by discarding the message that has been waiting the longest
+    #[default]
+    DropOldest,
+    // keep the queue as it is and discard the message being published
+    DropNewest,
+    // keep the queue as it is and fail the publish
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+pub struct OfflineQueueFull;
+impl fmt::Display for OfflineQueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Not connected and the offline queue is full")
+    }
+}
+
+impl Error for OfflineQueueFull {}
+
+#[derive(Debug)]
+struct QueuedMessage {
+    message: Message,
+    expires_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct OfflineQueue {
+    messages: VecDeque<QueuedMessage>,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl OfflineQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        OfflineQueue {
+            messages: VecDeque::new(),
+            capacity,
+            policy,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Queues a message that is discarded if it's still queued after the expiry.
+    // Returns the message the overflow policy dropped, if any.
+    pub fn push(
+        &mut self,
+        message: Message,
+        expiry: Option<Duration>,
+    ) -> Result<Option<Message>, OfflineQueueFull> {
+        let now = Instant::now();
+        self.remove_expired(now);
+
+        let mut dropped = None;
+        if self.messages.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    dropped = self.messages.pop_front().map(|queued| queued.message);
+                    // with a capacity of 0 there's no room to make
+                    if self.capacity == 0 {
+                        return Ok(Some(message));
+                    }
+                }
+                OverflowPolicy::DropNewest => return Ok(Some(message)),
+                OverflowPolicy::Reject => return Err(OfflineQueueFull),
+            }
+        }
+
+        self.messages.push_back(QueuedMessage {
+            message,
+            expires_at: expiry.map(|expiry| now + expiry),
+        });
+        Ok(dropped)
+    }
+
+    // Takes the oldest message that hasn't expired, if the condition accepts it
+    pub fn pop_if<F>(&mut self, condition: F) -> Option<Message>
+    where
+        F: FnOnce(&Message) -> bool,
+    {
+        self.remove_expired(Instant::now());
+        if condition(&self.messages.front()?.message) {
+            self.messages.pop_front().map(|queued| queued.message)
+        } else {
+            None
+        }
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        self.messages
+            .retain(|queued| queued.expires_at.is_none_or(|expires_at| expires_at > now));
+    }
+}
+
+#[cfg(test)]
+mod offline_queue_tests {
+    use std::time::Duration;
+
+    use crate::{connect_packet::QoS, publish_packet::Message};
+
+    use super::{OfflineQueue, OverflowPolicy};
+
+    fn message(payload: &str) -> Message {
+        Message {
+            topic: String::from("sensors/kitchen/temp"),
+            payload: payload.as_bytes().to_vec(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+        }
+    }
+
+    // Empties the queue, returning the payloads that haven't expired, oldest first
+    fn payloads(queue: &mut OfflineQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop_if(|_| true))
+            .map(|message| String::from_utf8(message.payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn drain_in_order_test() {
+        let mut queue = OfflineQueue::new(10, OverflowPolicy::DropOldest);
+        for payload in ["1", "2", "3"] {
+            assert!(queue.push(message(payload), None).unwrap().is_none());
+        }
+        assert_eq!(queue.len(), 3);
+        assert_eq!(payloads(&mut queue), vec!["1", "2", "3"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_oldest_test() {
+        let mut queue = OfflineQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(message("1"), None).unwrap();
+        queue.push(message("2"), None).unwrap();
+        let dropped = queue.push(message("3"), None).unwrap();
+        assert_eq!(dropped, Some(message("1")));
+        assert_eq!(payloads(&mut queue), vec!["2", "3"]);
+    }
+
+    #[test]
+    fn drop_newest_test() {
+        let mut queue = OfflineQueue::new(2, OverflowPolicy::DropNewest);
+        queue.push(message("1"), None).unwrap();
+        queue.push(message("2"), None).unwrap();
+        let dropped = queue.push(message("3"), None).unwrap();
+        assert_eq!(dropped, Some(message("3")));
+        assert_eq!(payloads(&mut queue), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn reject_test() {
+        let mut queue = OfflineQueue::new(1, OverflowPolicy::Reject);
+        queue.push(message("1"), None).unwrap();
+        assert!(queue.push(message("2"), None).is_err());
+        assert_eq!(payloads(&mut queue), vec!["1"]);
+    }
+
+    #[test]
+    fn zero_capacity_test() {
+        let mut queue = OfflineQueue::new(0, OverflowPolicy::DropOldest);
+        assert_eq!(queue.push(message("1"), None).unwrap(), Some(message("1")));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn pop_if_test() {
+        let mut queue = OfflineQueue::new(2, OverflowPolicy::DropOldest);
+        queue.push(message("1"), None).unwrap();
+        queue.push(message("2"), None).unwrap();
+        assert!(queue.pop_if(|_| false).is_none());
+        assert_eq!(queue.pop_if(|_| true), Some(message("1")));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn expiry_test() {
+        let mut queue = OfflineQueue::new(2, OverflowPolicy::Reject);
+        queue.push(message("1"), Some(Duration::ZERO)).unwrap();
+        queue
+            .push(message("2"), Some(Duration::from_secs(60)))
+            .unwrap();
+        // the expired message doesn't take up room
+        queue.push(message("3"), None).unwrap();
+        assert_eq!(payloads(&mut queue), vec!["2", "3"]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{error::Error, fmt};
+
+// 2.3.1 Packet Identifier
+// SUBSCRIBE, UNSUBSCRIBE, and PUBLISH (in cases where QoS > 0) Control Packets MUST contain
+// a non-zero 16-bit Packet Identifier [MQTT-2.3.1-1].
+// Each time a Client sends a new packet of one of these types it MUST assign it
+// a currently unused Packet Identifier [MQTT-2.3.1-2].
+// The Packet Identifier becomes available for reuse after the Client has processed
+// the corresponding acknowledgement packet (PUBACK, PUBCOMP, SUBACK or UNSUBACK).
+
+const PACKET_ID_COUNT: usize = u16::MAX as usize + 1;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+#[derive(Debug, Clone)]
+pub struct PacketIdsExhausted;
+
+impl fmt::Display for PacketIdsExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "All packet identifiers are in flight")
+    }
+}
+
+impl Error for PacketIdsExhausted {}
+
+pub struct PacketIdAllocator {
+    // one bit per packet id, set while the id is in flight
+    in_flight: Box<[u64]>,
+    in_flight_count: usize,
+    last_packet_id: u16,
+}
+
+impl Default for PacketIdAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for PacketIdAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacketIdAllocator")
+            .field("in_flight_count", &self.in_flight_count)
+            .field("last_packet_id", &self.last_packet_id)
+            .finish()
+    }
+}
+
+impl PacketIdAllocator {
+    pub fn new() -> Self {
+        PacketIdAllocator {
+            in_flight: vec![0u64; PACKET_ID_COUNT / BITS_PER_WORD].into_boxed_slice(),
+            in_flight_count: 0,
+            last_packet_id: 0,
+        }
+    }
+
+    // Hands out the next free id after the last one, wrapping around and skipping 0
+    // and every id that is still in flight.
+    pub fn acquire(&mut self) -> Result<u16, PacketIdsExhausted> {
+        if self.in_flight_count == PACKET_ID_COUNT - 1 {
+            return Err(PacketIdsExhausted);
+        }
+
+        let mut packet_id = self.last_packet_id;
+        loop {
+            packet_id = packet_id.checked_add(1).unwrap_or(1);
+            if !self.is_in_flight(packet_id) {
+                break;
+            }
+        }
+
+        self.mark(packet_id);
+        self.last_packet_id = packet_id;
+        Ok(packet_id)
+    }
+
+    // Marks a specific id as in flight, e.g. one that was restored from a previous session.
+    // Returns false if the id was already taken.
+    pub fn reserve(&mut self, packet_id: u16) -> bool {
+        if packet_id == 0 || self.is_in_flight(packet_id) {
+            return false;
+        }
+        self.mark(packet_id);
+        true
+    }
+
+    // Returns true if the id was in flight
+    pub fn release(&mut self, packet_id: u16) -> bool {
+        if !self.is_in_flight(packet_id) {
+            return false;
+        }
+        let (word, bit) = PacketIdAllocator::position(packet_id);
+        self.in_flight[word] &= !bit;
+        self.in_flight_count -= 1;
+        true
+    }
+
+    pub fn is_in_flight(&self, packet_id: u16) -> bool {
+        let (word, bit) = PacketIdAllocator::position(packet_id);
+        self.in_flight[word] & bit != 0
+    }
+
+    pub fn in_flight_count(&self) -> usize {
+        self.in_flight_count
+    }
+
+    fn mark(&mut self, packet_id: u16) {
+        let (word, bit) = PacketIdAllocator::position(packet_id);
+        self.in_flight[word] |= bit;
+        self.in_flight_count += 1;
+    }
+
+    fn position(packet_id: u16) -> (usize, u64) {
+        let index = packet_id as usize;
+        (index / BITS_PER_WORD, 1u64 << (index % BITS_PER_WORD))
+    }
+}
+
+#[cfg(test)]
+mod packet_id_allocator_tests {
+    use super::PacketIdAllocator;
+
+    #[test]
+    fn acquire_test() {
+        let mut allocator = PacketIdAllocator::new();
+        assert_eq!(allocator.acquire().unwrap(), 1);
+        assert_eq!(allocator.acquire().unwrap(), 2);
+        assert_eq!(allocator.in_flight_count(), 2);
+    }
+
+    #[test]
+    fn release_test() {
+        let mut allocator = PacketIdAllocator::new();
+        let packet_id = allocator.acquire().unwrap();
+        assert!(allocator.is_in_flight(packet_id));
+        assert!(allocator.release(packet_id));
+        assert!(!allocator.is_in_flight(packet_id));
+        assert!(!allocator.release(packet_id));
+        assert_eq!(allocator.in_flight_count(), 0);
+    }
+
+    #[test]
+    fn skips_zero_and_in_flight_ids_test() {
+        let mut allocator = PacketIdAllocator::new();
+        allocator.last_packet_id = u16::MAX - 1;
+        assert!(allocator.reserve(1));
+        assert_eq!(allocator.acquire().unwrap(), u16::MAX);
+        // wraps around past 0 and the reserved 1
+        assert_eq!(allocator.acquire().unwrap(), 2);
+    }
+
+    #[test]
+    fn reserve_test() {
+        let mut allocator = PacketIdAllocator::new();
+        assert!(!allocator.reserve(0));
+        assert!(allocator.reserve(7));
+        assert!(!allocator.reserve(7));
+        assert_eq!(allocator.in_flight_count(), 1);
+    }
+
+    #[test]
+    fn exhausted_test() {
+        let mut allocator = PacketIdAllocator::new();
+        for _ in 1..=u16::MAX {
+            allocator.acquire().unwrap();
+        }
+        assert!(allocator.acquire().is_err());
+
+        allocator.release(300);
+        assert_eq!(allocator.acquire().unwrap(), 300);
+    }
+}
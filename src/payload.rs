@@ -0,0 +1,120 @@
+use std::io;
+
+#[cfg(any(feature = "json", feature = "cbor"))]
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::publish_packet::Message;
+
+// Encoding and decoding of typed payloads, JSON with the json feature and CBOR with the cbor feature.
+// MQTT 3.1.1 has no content-type or payload-format-indicator property, so the receiver has to
+// know which encoding a topic uses.
+// Both encoding and decoding errors are reported as io::Errors, InvalidInput and InvalidData.
+
+#[cfg(feature = "json")]
+pub fn to_json<T: Serialize + ?Sized>(value: &T) -> io::Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+}
+
+#[cfg(feature = "json")]
+pub fn from_json<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+    serde_json::from_slice(payload)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(feature = "cbor")]
+pub fn to_cbor<T: Serialize + ?Sized>(value: &T) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    ciborium::into_writer(value, &mut payload)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error.to_string()))?;
+    Ok(payload)
+}
+
+#[cfg(feature = "cbor")]
+pub fn from_cbor<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+    ciborium::from_reader(payload)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error.to_string()))
+}
+
+impl Message {
+    #[cfg(feature = "json")]
+    pub fn json<T: DeserializeOwned>(&self) -> io::Result<T> {
+        from_json(&self.payload)
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: DeserializeOwned>(&self) -> io::Result<T> {
+        from_cbor(&self.payload)
+    }
+
+    // The payload as text, fails with InvalidData if it isn't UTF-8
+    pub fn text(&self) -> io::Result<&str> {
+        std::str::from_utf8(&self.payload)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+#[cfg(test)]
+mod payload_tests {
+    use std::io;
+
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    use serde::{Deserialize, Serialize};
+
+    use crate::{connect_packet::QoS, publish_packet::Message};
+
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        celsius: f32,
+    }
+
+    fn message(payload: Vec<u8>) -> Message {
+        Message {
+            topic: "a/b".to_string(),
+            payload,
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
+    }
+
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    fn reading() -> Reading {
+        Reading {
+            sensor: "kitchen".to_string(),
+            celsius: 21.5,
+        }
+    }
+
+    #[test]
+    fn text_test() {
+        assert_eq!(message(b"purr".to_vec()).text().unwrap(), "purr");
+        assert_eq!(
+            message(vec![0xff]).text().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_test() {
+        let payload = super::to_json(&reading()).unwrap();
+        assert_eq!(payload, br#"{"sensor":"kitchen","celsius":21.5}"#);
+        assert_eq!(message(payload).json::<Reading>().unwrap(), reading());
+        assert_eq!(
+            message(b"{".to_vec()).json::<Reading>().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_test() {
+        let payload = super::to_cbor(&reading()).unwrap();
+        assert_eq!(message(payload).cbor::<Reading>().unwrap(), reading());
+        assert_eq!(
+            message(vec![0xff]).cbor::<Reading>().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    sync::oneshot,
+    time::{self, Instant, Sleep},
+};
+
+use crate::sub_ack_packet::SubscribeReturnCode;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum AckError {
+    // the Server didn't acknowledge the packet in time
+    Timeout,
+    // the connection was dropped or replaced before the acknowledgement arrived
+    ConnectionLost,
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AckError::Timeout => write!(f, "Timed out waiting for an acknowledgement"),
+            AckError::ConnectionLost => {
+                write!(f, "Connection lost while waiting for an acknowledgement")
+            }
+        }
+    }
+}
+
+impl Error for AckError {}
+
+// For the blocking client, which reports everything as io errors
+impl From<AckError> for io::Error {
+    fn from(error: AckError) -> Self {
+        let kind = match error {
+            AckError::Timeout => io::ErrorKind::TimedOut,
+            AckError::ConnectionLost => io::ErrorKind::ConnectionAborted,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+// Resolves with the content of the acknowledgement matching a packet id,
+// or with an AckError if it doesn't arrive within the timeout.
+// The timeout counts from when the packet was registered, not from the first poll.
+pub struct AckFuture<T> {
+    receiver: oneshot::Receiver<T>,
+    deadline: Instant,
+    // created on the first poll, which is the first time there's sure to be a runtime
+    sleep: Option<Pin<Box<Sleep>>>,
+    on_timeout: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl<T> AckFuture<T> {
+    fn new(receiver: oneshot::Receiver<T>, timeout: Duration) -> Self {
+        AckFuture {
+            receiver,
+            deadline: Instant::now() + timeout,
+            sleep: None,
+            on_timeout: None,
+        }
+    }
+
+    // Runs once the acknowledgement is given up on, to drop the pending entry and free its packet id
+    pub fn on_timeout(mut self, on_timeout: impl FnOnce() + Send + Sync + 'static) -> Self {
+        self.on_timeout = Some(Box::new(on_timeout));
+        self
+    }
+}
+
+impl<T> Future for AckFuture<T> {
+    type Output = Result<T, AckError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(result) = Pin::new(&mut self.receiver).poll(cx) {
+            return Poll::Ready(result.map_err(|_| AckError::ConnectionLost));
+        }
+
+        let deadline = self.deadline;
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Box::pin(time::sleep_until(deadline)));
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(_) => {
+                if let Some(on_timeout) = self.on_timeout.take() {
+                    on_timeout();
+                }
+                Poll::Ready(Err(AckError::Timeout))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub type SubscribeFuture = AckFuture<Vec<SubscribeReturnCode>>;
+pub type UnsubscribeFuture = AckFuture<()>;
+
+// SUBSCRIBE and UNSUBSCRIBE packets waiting for their SUBACK or UNSUBACK, keyed by packet id
+#[derive(Debug, Default)]
+pub struct PendingAcks {
+    subscribes: HashMap<u16, oneshot::Sender<Vec<SubscribeReturnCode>>>,
+    unsubscribes: HashMap<u16, oneshot::Sender<()>>,
+}
+
+impl PendingAcks {
+    pub fn new() -> Self {
+        PendingAcks::default()
+    }
+
+    pub fn subscribe(&mut self, packet_id: u16, timeout: Duration) -> SubscribeFuture {
+        let (sender, receiver) = oneshot::channel();
+        self.subscribes.insert(packet_id, sender);
+        AckFuture::new(receiver, timeout)
+    }
+
+    pub fn unsubscribe(&mut self, packet_id: u16, timeout: Duration) -> UnsubscribeFuture {
+        let (sender, receiver) = oneshot::channel();
+        self.unsubscribes.insert(packet_id, sender);
+        AckFuture::new(receiver, timeout)
+    }
+
+    // Returns false if nobody was waiting for this packet id
+    pub fn complete_subscribe(
+        &mut self,
+        packet_id: u16,
+        return_codes: Vec<SubscribeReturnCode>,
+    ) -> bool {
+        match self.subscribes.remove(&packet_id) {
+            // the receiver may have timed out already, that's fine
+            Some(sender) => {
+                let _ = sender.send(return_codes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns false if nobody was waiting for this packet id
+    pub fn complete_unsubscribe(&mut self, packet_id: u16) -> bool {
+        match self.unsubscribes.remove(&packet_id) {
+            Some(sender) => {
+                let _ = sender.send(());
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Fails the future waiting for this packet id with ConnectionLost, e.g. because its
+    // packet couldn't be sent. Returns false if nobody was waiting for it.
+    pub fn abandon(&mut self, packet_id: u16) -> bool {
+        self.subscribes.remove(&packet_id).is_some()
+            || self.unsubscribes.remove(&packet_id).is_some()
+    }
+
+    // Fails every pending future with ConnectionLost and returns the packet ids they were using
+    pub fn clear(&mut self) -> Vec<u16> {
+        self.subscribes
+            .drain()
+            .map(|(packet_id, _)| packet_id)
+            .chain(self.unsubscribes.drain().map(|(packet_id, _)| packet_id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod pending_acks_tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use crate::{connect_packet::QoS, sub_ack_packet::SubscribeReturnCode};
+
+    use super::{AckError, PendingAcks};
+
+    #[tokio::test]
+    async fn subscribe_resolves_test() {
+        let mut pending_acks = PendingAcks::new();
+        let suback = pending_acks.subscribe(1, Duration::from_secs(5));
+
+        assert!(pending_acks
+            .complete_subscribe(1, vec![SubscribeReturnCode::Success(QoS::AtLeastOnce)]));
+        assert!(!pending_acks.complete_subscribe(1, vec![]));
+
+        assert_eq!(
+            suback.await,
+            Ok(vec![SubscribeReturnCode::Success(QoS::AtLeastOnce)])
+        );
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_resolves_test() {
+        let mut pending_acks = PendingAcks::new();
+        let unsuback = pending_acks.unsubscribe(2, Duration::from_secs(5));
+        assert!(pending_acks.complete_unsubscribe(2));
+        assert_eq!(unsuback.await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn timeout_test() {
+        let mut pending_acks = PendingAcks::new();
+        let suback = pending_acks.subscribe(1, Duration::from_millis(10));
+        assert_eq!(suback.await, Err(AckError::Timeout));
+    }
+
+    #[tokio::test]
+    async fn timeout_from_registration_test() {
+        let mut pending_acks = PendingAcks::new();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&timed_out);
+        let suback = pending_acks
+            .subscribe(1, Duration::from_millis(50))
+            .on_timeout(move || flag.store(true, Ordering::SeqCst));
+
+        // polled late, the timeout still ends 50ms after the subscribe
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let start = Instant::now();
+        assert_eq!(suback.await, Err(AckError::Timeout));
+        assert!(start.elapsed() < Duration::from_millis(40));
+        assert!(timed_out.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn connection_lost_test() {
+        let mut pending_acks = PendingAcks::new();
+        let suback = pending_acks.subscribe(1, Duration::from_secs(5));
+        let unsuback = pending_acks.unsubscribe(2, Duration::from_secs(5));
+
+        let mut packet_ids = pending_acks.clear();
+        packet_ids.sort();
+
+        assert_eq!(packet_ids, vec![1, 2]);
+        assert_eq!(suback.await, Err(AckError::ConnectionLost));
+        assert_eq!(unsuback.await, Err(AckError::ConnectionLost));
+    }
+
+    #[tokio::test]
+    async fn abandon_test() {
+        let mut pending_acks = PendingAcks::new();
+        let suback = pending_acks.subscribe(1, Duration::from_secs(5));
+        assert!(pending_acks.abandon(1));
+        assert!(!pending_acks.abandon(1));
+        assert_eq!(suback.await, Err(AckError::ConnectionLost));
+    }
+}
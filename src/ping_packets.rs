@@ -1,7 +1,6 @@
-use crate::{
-    control_packets::{ControlPacketFlags, Encodable, FixedHeader},
-    ControlPacketType,
-};
+use std::io;
+
+use crate::control_packets::{ControlPacketFlags, ControlPacketType, Encodable, FixedHeader};
 
 pub struct PingReqPacket {
     pub fixed_header: FixedHeader,
@@ -13,6 +12,12 @@ impl Encodable for PingReqPacket {
     }
 }
 
+impl Default for PingReqPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PingReqPacket {
     pub fn new() -> PingReqPacket {
         PingReqPacket {
@@ -30,11 +35,13 @@ pub struct PingRespPacket {
     pub fixed_header: FixedHeader,
 }
 
-impl From<&[u8]> for PingRespPacket {
-    fn from(bytes: &[u8]) -> Self {
-        PingRespPacket {
-            fixed_header: FixedHeader::from(bytes),
-        }
+impl TryFrom<&[u8]> for PingRespPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        Ok(PingRespPacket {
+            fixed_header: FixedHeader::try_from(bytes)?,
+        })
     }
 }
 
@@ -44,6 +51,12 @@ impl Encodable for PingRespPacket {
     }
 }
 
+impl Default for PingRespPacket {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PingRespPacket {
     pub fn new() -> PingRespPacket {
         PingRespPacket {
@@ -0,0 +1,618 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::happy_eyeballs;
+
+// Reaching the broker through a proxy. The client opens a TCP connection to the proxy and asks
+// it for a tunnel to the broker, everything else (TLS, WebSockets, MQTT) runs through the tunnel.
+// The broker's host name is passed on as is, so it's the proxy that resolves it.
+
+// RFC 1929 sends the user name and the password with a one byte length
+pub const MAX_SOCKS5_CREDENTIAL_LENGTH: usize = u8::MAX as usize;
+
+// The longest response header an HTTP proxy may send before the tunnel starts
+const MAX_HTTP_RESPONSE_LENGTH: usize = 8192;
+
+const SOCKS_VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USER_NAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const USER_NAME_PASSWORD_VERSION: u8 = 0x01;
+const CONNECT: u8 = 0x01;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Credentials {
+    pub user_name: String,
+    pub password: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Proxy {
+    // RFC 1928, with the user name and password authentication of RFC 1929 if there are credentials
+    Socks5 {
+        address: String,
+        credentials: Option<Credentials>,
+    },
+    // RFC 9110 CONNECT, with Basic authentication if there are credentials
+    HttpConnect {
+        address: String,
+        credentials: Option<Credentials>,
+    },
+}
+
+impl Proxy {
+    pub fn address(&self) -> &str {
+        match self {
+            Proxy::Socks5 { address, .. } | Proxy::HttpConnect { address, .. } => address,
+        }
+    }
+
+    pub fn credentials(&self) -> Option<&Credentials> {
+        match self {
+            Proxy::Socks5 { credentials, .. } | Proxy::HttpConnect { credentials, .. } => {
+                credentials.as_ref()
+            }
+        }
+    }
+
+    // Connects to the proxy and returns the stream once it's tunneled to the target "host:port",
+    // and the proxy's address it connected to
+    pub(crate) async fn connect(
+        &self,
+        target: &str,
+        attempt_delay: Duration,
+    ) -> io::Result<(TcpStream, SocketAddr)> {
+        let (mut stream, proxy_address) =
+            happy_eyeballs::connect(self.address(), attempt_delay).await?;
+        match self {
+            Proxy::Socks5 { .. } => {
+                socks5_handshake(&mut stream, target, self.credentials()).await?
+            }
+            Proxy::HttpConnect { .. } => {
+                http_connect_handshake(&mut stream, target, self.credentials()).await?
+            }
+        }
+        Ok((stream, proxy_address))
+    }
+}
+
+async fn socks5_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: &str,
+    credentials: Option<&Credentials>,
+) -> io::Result<()> {
+    let (host, port) = split_host_port(target)?;
+
+    let methods: &[u8] = match credentials {
+        Some(_) => &[NO_AUTHENTICATION, USER_NAME_PASSWORD],
+        None => &[NO_AUTHENTICATION],
+    };
+    let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+    stream.flush().await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(invalid_reply("the proxy doesn't speak SOCKS5"));
+    }
+    match (choice[1], credentials) {
+        (NO_AUTHENTICATION, _) => {}
+        (USER_NAME_PASSWORD, Some(credentials)) => authenticate(stream, credentials).await?,
+        (NO_ACCEPTABLE_METHOD, _) => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the SOCKS5 proxy requires an authentication method the client doesn't offer",
+            ))
+        }
+        _ => {
+            return Err(invalid_reply(
+                "the SOCKS5 proxy chose a method that wasn't offered",
+            ))
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let length = u8::try_from(host.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "the host name is too long")
+            })?;
+            request.push(DOMAIN_NAME);
+            request.push(length);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    // | version | reply | reserved | address type | bound address | bound port |
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(invalid_reply("the proxy doesn't speak SOCKS5"));
+    }
+    if reply[1] != 0x00 {
+        return Err(socks5_error(reply[1]));
+    }
+    let address_length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => stream.read_u8().await? as usize,
+        _ => return Err(invalid_reply("unknown address type in the SOCKS5 reply")),
+    };
+    // the address the proxy connects from is of no use to the client
+    let mut bound = vec![0u8; address_length + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+// RFC 1929
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    credentials: &Credentials,
+) -> io::Result<()> {
+    let mut request = vec![USER_NAME_PASSWORD_VERSION];
+    for field in [&credentials.user_name, &credentials.password] {
+        let length = u8::try_from(field.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "SOCKS5 credentials are limited to 255 bytes",
+            )
+        })?;
+        request.push(length);
+        request.extend_from_slice(field.as_bytes());
+    }
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await?;
+    match status[1] {
+        0x00 => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the SOCKS5 proxy rejected the credentials",
+        )),
+    }
+}
+
+fn socks5_error(reply: u8) -> io::Error {
+    let (kind, reason) = match reply {
+        0x02 => (
+            io::ErrorKind::PermissionDenied,
+            "not allowed by the ruleset",
+        ),
+        0x03 => (io::ErrorKind::NetworkUnreachable, "network unreachable"),
+        0x04 => (io::ErrorKind::HostUnreachable, "host unreachable"),
+        0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+        0x07 | 0x08 => (io::ErrorKind::Unsupported, "not supported"),
+        _ => (io::ErrorKind::Other, "general failure"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {}", reason))
+}
+
+async fn http_connect_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    target: &str,
+    credentials: Option<&Credentials>,
+) -> io::Result<()> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(credentials) = credentials {
+        let user_pass = format!("{}:{}", credentials.user_name, credentials.password);
+        request.push_str(&format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            base64(user_pass.as_bytes())
+        ));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // read one byte at a time, whatever follows the header already belongs to the tunnel
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_HTTP_RESPONSE_LENGTH {
+            return Err(invalid_reply("the proxy's response header is too long"));
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let status_line = response
+        .split(|&byte| byte == b'\r')
+        .next()
+        .unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    let mut parts = status_line.splitn(3, ' ');
+    if !parts
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(invalid_reply("the proxy doesn't speak HTTP/1.x"));
+    }
+    match parts.next() {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some("407") => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("HTTP proxy: {}", status_line),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("HTTP proxy: {}", status_line),
+        )),
+    }
+}
+
+fn invalid_reply(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+// Splits "host:port" and "[v6]:port"
+fn split_host_port(address: &str) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected host:port, got {}", address),
+        )
+    };
+    let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.strip_suffix(']').ok_or_else(invalid)?,
+        None => host,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port.parse().map_err(|_| invalid())?))
+}
+
+// The standard alphabet with padding, for the Basic authentication header
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+pub(crate) mod proxy_tests {
+    use std::{
+        io,
+        net::{Ipv4Addr, Ipv6Addr},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+
+    use crate::happy_eyeballs::DEFAULT_CONNECTION_ATTEMPT_DELAY;
+
+    use super::{base64, split_host_port, Credentials, Proxy, IPV4, IPV6};
+
+    pub(crate) fn credentials(user_name: &str, password: &str) -> Option<Credentials> {
+        Some(Credentials {
+            user_name: user_name.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    // A stand-in for a SOCKS5 proxy that requires user name "kitty" and password "meow" and
+    // tunnels one connection. Resolves with the target the client asked for.
+    pub(crate) async fn socks5_proxy() -> (String, JoinHandle<io::Result<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let proxy = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await?;
+            let mut greeting = [0u8; 2];
+            client.read_exact(&mut greeting).await?;
+            let mut methods = vec![0u8; greeting[1] as usize];
+            client.read_exact(&mut methods).await?;
+            if !methods.contains(&0x02) {
+                client.write_all(&[0x05, 0xff]).await?;
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            client.write_all(&[0x05, 0x02]).await?;
+
+            let mut fields = Vec::new();
+            client.read_u8().await?;
+            for _ in 0..2 {
+                let mut field = vec![0u8; client.read_u8().await? as usize];
+                client.read_exact(&mut field).await?;
+                fields.push(field);
+            }
+            if fields != [b"kitty".to_vec(), b"meow".to_vec()] {
+                client.write_all(&[0x01, 0x01]).await?;
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            client.write_all(&[0x01, 0x00]).await?;
+
+            let mut request = [0u8; 4];
+            client.read_exact(&mut request).await?;
+            let length = match request[3] {
+                0x01 => 4,
+                0x04 => 16,
+                _ => client.read_u8().await? as usize,
+            };
+            let mut target = vec![0u8; length];
+            client.read_exact(&mut target).await?;
+            let target = socks5_target(request[3], &target, client.read_u16().await?);
+            let mut broker = match TcpStream::connect(&target).await {
+                Ok(broker) => broker,
+                Err(error) => {
+                    client
+                        .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                        .await?;
+                    return Err(error);
+                }
+            };
+            client
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
+                .await?;
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut broker).await;
+            Ok(target)
+        });
+        (address, proxy)
+    }
+
+    // A stand-in for an HTTP proxy that requires user name "kitty" and password "meow" and
+    // tunnels one connection. Resolves with the CONNECT request line.
+    pub(crate) async fn http_proxy() -> (String, JoinHandle<io::Result<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let proxy = tokio::spawn(async move {
+            let (mut client, _) = listener.accept().await?;
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(client.read_u8().await?);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let request_line = request.lines().next().unwrap().to_string();
+            if !request.contains("Proxy-Authorization: Basic a2l0dHk6bWVvdw==\r\n") {
+                client
+                    .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                    .await?;
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            let target = request_line.split(' ').nth(1).unwrap();
+            let mut broker = TcpStream::connect(&target).await?;
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\nVia: stand-in\r\n\r\n")
+                .await?;
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut broker).await;
+            Ok(request_line)
+        });
+        (address, proxy)
+    }
+
+    // What a SOCKS5 proxy's CONNECT request asks to connect to
+    fn socks5_target(address_type: u8, address: &[u8], port: u16) -> String {
+        match address_type {
+            IPV4 => format!(
+                "{}:{}",
+                Ipv4Addr::from(<[u8; 4]>::try_from(address).unwrap()),
+                port
+            ),
+            IPV6 => format!(
+                "[{}]:{}",
+                Ipv6Addr::from(<[u8; 16]>::try_from(address).unwrap()),
+                port
+            ),
+            _ => format!("{}:{}", String::from_utf8_lossy(address), port),
+        }
+    }
+
+    // Echoes the first four bytes of one connection
+    async fn echo_server() -> (u16, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+        });
+        (port, server)
+    }
+
+    async fn echo(stream: &mut TcpStream) {
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+
+    #[test]
+    fn split_host_port_test() {
+        assert_eq!(
+            split_host_port("broker.local:1883").unwrap(),
+            ("broker.local", 1883)
+        );
+        assert_eq!(split_host_port("[::1]:8883").unwrap(), ("::1", 8883));
+        for invalid in ["broker.local", ":1883", "broker.local:port", "[::1:8883"] {
+            assert_eq!(
+                split_host_port(invalid).unwrap_err().kind(),
+                io::ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    fn base64_test() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"kitty:meow"), "a2l0dHk6bWVvdw==");
+    }
+
+    #[tokio::test]
+    async fn socks5_test() {
+        let (port, server) = echo_server().await;
+        let (address, proxy_task) = socks5_proxy().await;
+        let proxy = Proxy::Socks5 {
+            address,
+            credentials: credentials("kitty", "meow"),
+        };
+
+        let (mut stream, proxy_address) = proxy
+            .connect(
+                &format!("localhost:{}", port),
+                DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            )
+            .await
+            .unwrap();
+        assert_eq!(proxy_address.to_string(), proxy.address());
+        echo(&mut stream).await;
+        server.await.unwrap();
+        drop(stream);
+        assert_eq!(
+            proxy_task.await.unwrap().unwrap(),
+            format!("localhost:{}", port)
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_ip_address_test() {
+        let (port, server) = echo_server().await;
+        let (address, proxy_task) = socks5_proxy().await;
+        let proxy = Proxy::Socks5 {
+            address,
+            credentials: credentials("kitty", "meow"),
+        };
+
+        let target = format!("127.0.0.1:{}", port);
+        let (mut stream, _) = proxy
+            .connect(&target, DEFAULT_CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap();
+        echo(&mut stream).await;
+        server.await.unwrap();
+        drop(stream);
+        assert_eq!(proxy_task.await.unwrap().unwrap(), target);
+    }
+
+    #[tokio::test]
+    async fn socks5_authentication_test() {
+        let (address, proxy_task) = socks5_proxy().await;
+        let proxy = Proxy::Socks5 {
+            address,
+            credentials: credentials("kitty", "woof"),
+        };
+        let error = proxy
+            .connect("localhost:1883", DEFAULT_CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(proxy_task.await.unwrap().is_err());
+
+        // without credentials there's no method both sides accept
+        let (address, proxy_task) = socks5_proxy().await;
+        let proxy = Proxy::Socks5 {
+            address,
+            credentials: None,
+        };
+        let error = proxy
+            .connect("localhost:1883", DEFAULT_CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(proxy_task.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn socks5_refused_test() {
+        // a port nobody listens on
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let (address, proxy_task) = socks5_proxy().await;
+        let proxy = Proxy::Socks5 {
+            address,
+            credentials: credentials("kitty", "meow"),
+        };
+        let error = proxy
+            .connect(
+                &format!("127.0.0.1:{}", port),
+                DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(proxy_task.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn http_connect_test() {
+        let (port, server) = echo_server().await;
+        let (address, proxy_task) = http_proxy().await;
+        let proxy = Proxy::HttpConnect {
+            address,
+            credentials: credentials("kitty", "meow"),
+        };
+
+        let (mut stream, _) = proxy
+            .connect(
+                &format!("localhost:{}", port),
+                DEFAULT_CONNECTION_ATTEMPT_DELAY,
+            )
+            .await
+            .unwrap();
+        echo(&mut stream).await;
+        server.await.unwrap();
+        drop(stream);
+        assert_eq!(
+            proxy_task.await.unwrap().unwrap(),
+            format!("CONNECT localhost:{} HTTP/1.1", port)
+        );
+    }
+
+    #[tokio::test]
+    async fn http_authentication_test() {
+        let (address, proxy_task) = http_proxy().await;
+        let proxy = Proxy::HttpConnect {
+            address,
+            credentials: None,
+        };
+        let error = proxy
+            .connect("localhost:1883", DEFAULT_CONNECTION_ATTEMPT_DELAY)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("407"));
+        assert!(proxy_task.await.unwrap().is_err());
+    }
+}
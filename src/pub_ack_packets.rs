@@ -0,0 +1,242 @@
+use std::io;
+
+use crate::control_packets::{
+    decode_packet_id, malformed, ControlPacketFlags, ControlPacketType, Encodable, FixedHeader,
+};
+
+// 3.4. PUBACK  - Publish acknowledgement (QoS 1)
+// 3.5. PUBREC  - Publish received (QoS 2 publish received, part 1)
+// 3.6. PUBREL  - Publish release (QoS 2 publish received, part 2)
+// 3.7. PUBCOMP - Publish complete (QoS 2 publish received, part 3)
+//
+// All four packets share the same layout: a fixed header with a remaining length of 2,
+// followed by the Packet Identifier of the PUBLISH they acknowledge.
+//  ----------------------------------------------------------------------------------------
+// | bit    |    7    |    6    |    5    |    4    |    3    |    2    |    1    |    0    |
+//  ----------------------------------------------------------------------------------------
+// | byte 1 |        MQTT control packet type       |         Packet type specific flags    |
+//  ----------------------------------------------------------------------------------------
+// | byte 2 |                            Remaining Length (2)                               |
+//  ----------------------------------------------------------------------------------------
+// | byte 3 |                             Packet identifier MSB                             |
+//  ----------------------------------------------------------------------------------------
+// | byte 4 |                             Packet identifier LSB                             |
+//  ----------------------------------------------------------------------------------------
+//
+// Exactly once delivery (QoS 2) runs as follows:
+//
+//   Sender                          Receiver
+//     | -------- PUBLISH (id) -------> |  store id, deliver the message once
+//     | <------- PUBREC  (id) -------- |
+//     | -------- PUBREL  (id) -------> |  forget id
+//     | <------- PUBCOMP (id) -------- |
+
+const PACKET_ID_REMAINING_LENGTH: usize = 2;
+
+// The fixed header and the packet id of a whole packet
+fn decode_with_packet_id(bytes: &[u8], packet: &str) -> io::Result<(FixedHeader, u16)> {
+    let (fixed_header, variable_header) = FixedHeader::split(bytes)?;
+    let packet_id = decode_packet_id(variable_header).ok_or_else(|| malformed(packet))?;
+    Ok((fixed_header, packet_id))
+}
+
+fn encode_with_packet_id(fixed_header: &FixedHeader, packet_id: u16) -> Vec<u8> {
+    let mut vec: Vec<u8> = Vec::new();
+    vec.extend_from_slice(&fixed_header.encode());
+    vec.extend_from_slice(&packet_id.to_be_bytes());
+    vec
+}
+
+#[derive(Debug)]
+pub struct PubAckPacket {
+    pub fixed_header: FixedHeader,
+    pub packet_id: u16,
+}
+
+impl PubAckPacket {
+    pub fn new(packet_id: u16) -> PubAckPacket {
+        PubAckPacket {
+            fixed_header: FixedHeader {
+                packet_type: ControlPacketType::PubAck,
+                packet_flags: ControlPacketFlags::PUB_ACK_FLAGS,
+                remaining_length: PACKET_ID_REMAINING_LENGTH,
+            },
+            packet_id,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for PubAckPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, packet_id) = decode_with_packet_id(bytes, "PUBACK")?;
+        Ok(PubAckPacket {
+            fixed_header,
+            packet_id,
+        })
+    }
+}
+
+impl Encodable for PubAckPacket {
+    fn encode(&self) -> Vec<u8> {
+        encode_with_packet_id(&self.fixed_header, self.packet_id)
+    }
+}
+
+#[derive(Debug)]
+pub struct PubRecPacket {
+    pub fixed_header: FixedHeader,
+    pub packet_id: u16,
+}
+
+impl PubRecPacket {
+    pub fn new(packet_id: u16) -> PubRecPacket {
+        PubRecPacket {
+            fixed_header: FixedHeader {
+                packet_type: ControlPacketType::PubRec,
+                packet_flags: ControlPacketFlags::PUB_REC_FLAGS,
+                remaining_length: PACKET_ID_REMAINING_LENGTH,
+            },
+            packet_id,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for PubRecPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, packet_id) = decode_with_packet_id(bytes, "PUBREC")?;
+        Ok(PubRecPacket {
+            fixed_header,
+            packet_id,
+        })
+    }
+}
+
+impl Encodable for PubRecPacket {
+    fn encode(&self) -> Vec<u8> {
+        encode_with_packet_id(&self.fixed_header, self.packet_id)
+    }
+}
+
+#[derive(Debug)]
+pub struct PubRelPacket {
+    pub fixed_header: FixedHeader,
+    pub packet_id: u16,
+}
+
+impl PubRelPacket {
+    pub fn new(packet_id: u16) -> PubRelPacket {
+        PubRelPacket {
+            fixed_header: FixedHeader {
+                packet_type: ControlPacketType::PubRel,
+                packet_flags: ControlPacketFlags::PUB_REL_FLAGS,
+                remaining_length: PACKET_ID_REMAINING_LENGTH,
+            },
+            packet_id,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for PubRelPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, packet_id) = decode_with_packet_id(bytes, "PUBREL")?;
+        Ok(PubRelPacket {
+            fixed_header,
+            packet_id,
+        })
+    }
+}
+
+impl Encodable for PubRelPacket {
+    fn encode(&self) -> Vec<u8> {
+        encode_with_packet_id(&self.fixed_header, self.packet_id)
+    }
+}
+
+#[derive(Debug)]
+pub struct PubCompPacket {
+    pub fixed_header: FixedHeader,
+    pub packet_id: u16,
+}
+
+impl PubCompPacket {
+    pub fn new(packet_id: u16) -> PubCompPacket {
+        PubCompPacket {
+            fixed_header: FixedHeader {
+                packet_type: ControlPacketType::PubComp,
+                packet_flags: ControlPacketFlags::PUB_COMP_FLAGS,
+                remaining_length: PACKET_ID_REMAINING_LENGTH,
+            },
+            packet_id,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for PubCompPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, packet_id) = decode_with_packet_id(bytes, "PUBCOMP")?;
+        Ok(PubCompPacket {
+            fixed_header,
+            packet_id,
+        })
+    }
+}
+
+impl Encodable for PubCompPacket {
+    fn encode(&self) -> Vec<u8> {
+        encode_with_packet_id(&self.fixed_header, self.packet_id)
+    }
+}
+
+#[cfg(test)]
+mod pub_ack_packets_tests {
+    use crate::{
+        control_packets::{ControlPacketType, Encodable},
+        pub_ack_packets::{PubAckPacket, PubCompPacket, PubRecPacket, PubRelPacket},
+    };
+
+    #[test]
+    fn pub_ack_encode_test() {
+        let pub_ack_packet_bytes = PubAckPacket::new(0x0102).encode();
+        assert_eq!(pub_ack_packet_bytes, vec![0b0100_0000, 2, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn pub_rec_encode_test() {
+        let pub_rec_packet_bytes = PubRecPacket::new(7).encode();
+        assert_eq!(pub_rec_packet_bytes, vec![0b0101_0000, 2, 0, 7]);
+    }
+
+    #[test]
+    fn pub_rel_encode_test() {
+        // PUBREL has the reserved flags set to 0b0010 [MQTT-3.6.1-1]
+        let pub_rel_packet_bytes = PubRelPacket::new(7).encode();
+        assert_eq!(pub_rel_packet_bytes, vec![0b0110_0010, 2, 0, 7]);
+    }
+
+    #[test]
+    fn pub_comp_encode_test() {
+        let pub_comp_packet_bytes = PubCompPacket::new(7).encode();
+        assert_eq!(pub_comp_packet_bytes, vec![0b0111_0000, 2, 0, 7]);
+    }
+
+    #[test]
+    fn decode_test() {
+        let pub_rel_packet = PubRelPacket::try_from(&[0b0110_0010, 2, 0x12, 0x34][..]).unwrap();
+        assert_eq!(
+            pub_rel_packet.fixed_header.packet_type,
+            ControlPacketType::PubRel
+        );
+        assert_eq!(pub_rel_packet.packet_id, 0x1234);
+
+        let pub_comp_packet = PubCompPacket::try_from(&[0b0111_0000, 2, 0, 9][..]).unwrap();
+        assert_eq!(pub_comp_packet.packet_id, 9);
+    }
+}
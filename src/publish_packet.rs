@@ -1,23 +1,48 @@
-use std::error::Error;
+use std::{error::Error, io};
 
 use crate::{
-    control_packets::{as_u16_be, encode_remaining_length, Encodable, FixedHeader},
-    ControlPacketType,
+    connect_packet::QoS,
+    control_packets::{
+        as_u16_be, decode_packet_id, malformed, ControlPacketType, Encodable, FixedHeader,
+    },
 };
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
 pub struct PublishPacketFlags {
-    // duplicate_delivery: Option<bool>,
-    // qos: Option<QoS>,
-    // retain: Option<bool>
     byte_rep: u8,
 }
 
+impl PublishPacketFlags {
+    const DUP_MASK: u8 = 0b0000_1000;
+    const QOS_MASK: u8 = 0b0000_0110;
+    const RETAIN_MASK: u8 = 0b0000_0001;
+
+    pub fn new(dup: bool, qos: QoS, retain: bool) -> Self {
+        let mut byte_rep = (u8::from(qos) << 1) & PublishPacketFlags::QOS_MASK;
+        if dup {
+            byte_rep |= PublishPacketFlags::DUP_MASK;
+        }
+        if retain {
+            byte_rep |= PublishPacketFlags::RETAIN_MASK;
+        }
+        PublishPacketFlags { byte_rep }
+    }
+
+    pub fn dup(&self) -> bool {
+        self.byte_rep & PublishPacketFlags::DUP_MASK != 0
+    }
+
+    pub fn qos(&self) -> QoS {
+        ((self.byte_rep & PublishPacketFlags::QOS_MASK) >> 1).into()
+    }
+
+    pub fn retain(&self) -> bool {
+        self.byte_rep & PublishPacketFlags::RETAIN_MASK != 0
+    }
+}
+
 impl From<PublishPacketFlags> for u8 {
     fn from(value: PublishPacketFlags) -> Self {
-        // let dup_mask = 0b000_1000;
-        // let qos_mask= 0b0000_0110;
-        // let retain_mask = 0b0000_0001;
         value.byte_rep
     }
 }
@@ -35,6 +60,12 @@ pub struct Builder<'a> {
     payload: Option<&'a [u8]>,
 }
 
+impl<'a> Default for Builder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> Builder<'a> {
     pub fn new() -> Self {
         Builder {
@@ -85,7 +116,7 @@ impl<'a> Builder<'a> {
         remaining_length
     }
 
-    pub fn build(&mut self) -> Result<PublishPacket, Box<dyn Error>> {
+    pub fn build(&mut self) -> Result<PublishPacket<'_>, Box<dyn Error>> {
         let remaining_length = self.calc_remaining_length();
         Ok(PublishPacket {
             fixed_header: FixedHeader {
@@ -110,6 +141,10 @@ pub struct PublishPacket<'a> {
 }
 
 impl<'a> PublishPacket<'a> {
+    pub fn flags(&self) -> PublishPacketFlags {
+        self.fixed_header.packet_flags.into()
+    }
+
     pub fn new(flags: PublishPacketFlags, topic_name: &'a str, payload: &'a [u8]) -> Self {
         PublishPacket {
             fixed_header: FixedHeader {
@@ -117,9 +152,9 @@ impl<'a> PublishPacket<'a> {
                 packet_type: ControlPacketType::Publish,
                 remaining_length: 0,
             },
-            topic_name: topic_name,
+            topic_name,
             packet_id: None,
-            payload: payload,
+            payload,
         }
     }
 }
@@ -127,58 +162,88 @@ impl<'a> PublishPacket<'a> {
 impl<'a> Encodable for PublishPacket<'a> {
     fn encode(&self) -> Vec<u8> {
         let mut vec: Vec<u8> = Vec::new();
-        vec.extend_from_slice(&mut self.fixed_header.encode());
+        vec.extend_from_slice(&self.fixed_header.encode());
         vec.extend_from_slice(&(self.topic_name.len() as u16).to_be_bytes());
-        vec.extend_from_slice(&self.topic_name.as_bytes());
-        match self.packet_id {
-            Some(id) => vec.extend_from_slice(&id.to_be_bytes()),
-            None => {}
+        vec.extend_from_slice(self.topic_name.as_bytes());
+        if let Some(id) = self.packet_id {
+            vec.extend_from_slice(&id.to_be_bytes());
         }
-        vec.extend_from_slice(&self.payload);
+        vec.extend_from_slice(self.payload);
         vec
     }
 }
 
-impl<'a> From<&'a [u8]> for PublishPacket<'a> {
-    fn from(bytes: &'a [u8]) -> Self {
-        let fixed_header = FixedHeader::from(bytes);
-        let remaining_length_byte_count = encode_remaining_length(fixed_header.remaining_length)
-            .unwrap()
-            .len();
-
-        // TODO: No packet id at QoS 0
-        let mut cursor = 1 + remaining_length_byte_count;
-        let topic_length_si = cursor;
-        let topic_length_ei = topic_length_si + 2;
-        let topic_length = as_u16_be(&bytes[topic_length_si..topic_length_ei]);
-        cursor += 2;
-        let topic_ei = cursor + topic_length as usize;
-        let topic = std::str::from_utf8(&bytes[cursor..topic_ei]);
-        cursor = topic_ei;
-        let payload_ei =
-            cursor + fixed_header.remaining_length - (cursor - 1 - remaining_length_byte_count);
-        let payload = &bytes[cursor..payload_ei];
-
-        // | control packet type byte | remaining_length bytes | topic_length bytes | topic bytes | payload bytes |
-        //                                                                                        ^               ^
-        //                                                                                      cursor        payload_ei
-        //                                                     |                 remaining length                 |
-        // remaining_length - (cursor - 1 - rem_len_bytes)
-
-        Self {
-            fixed_header: FixedHeader::from(bytes),
-            packet_id: None,
-            topic_name: topic.unwrap(),
-            payload: payload,
+impl<'a> TryFrom<&'a [u8]> for PublishPacket<'a> {
+    type Error = io::Error;
+
+    fn try_from(bytes: &'a [u8]) -> io::Result<Self> {
+        let (fixed_header, rest) = FixedHeader::split(bytes)?;
+        let flags = PublishPacketFlags::from(fixed_header.packet_flags);
+        // a PUBLISH Packet MUST NOT have both QoS bits set to 1 [MQTT-3.3.1-4]
+        if flags.byte_rep & PublishPacketFlags::QOS_MASK == PublishPacketFlags::QOS_MASK {
+            return Err(malformed("PUBLISH"));
+        }
+
+        // | topic length | topic | packet id (QoS 1 and 2 only) | payload |
+        let topic_length = rest
+            .get(..2)
+            .map(as_u16_be)
+            .ok_or_else(|| malformed("PUBLISH"))? as usize;
+        let mut cursor = 2 + topic_length;
+        let topic_name = rest
+            .get(2..cursor)
+            .and_then(|topic| std::str::from_utf8(topic).ok())
+            .ok_or_else(|| malformed("PUBLISH"))?;
+        let packet_id = match flags.qos() {
+            QoS::AtMostOnce => None,
+            _ => {
+                let packet_id =
+                    decode_packet_id(&rest[cursor..]).ok_or_else(|| malformed("PUBLISH"))?;
+                cursor += 2;
+                Some(packet_id)
+            }
+        };
+
+        Ok(Self {
+            fixed_header,
+            packet_id,
+            topic_name,
+            payload: &rest[cursor..],
+        })
+    }
+}
+
+// An owned copy of an application message, for keeping it around after the receive buffer is gone
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl<'a> From<&PublishPacket<'a>> for Message {
+    fn from(packet: &PublishPacket<'a>) -> Self {
+        let flags = packet.flags();
+        Message {
+            topic: packet.topic_name.to_string(),
+            payload: packet.payload.to_vec(),
+            qos: flags.qos(),
+            retain: flags.retain(),
         }
     }
 }
 
 #[cfg(test)]
 mod publish_packet_tests {
-    use crate::control_packets::ControlPacketType;
+    use std::io;
+
+    use crate::{
+        connect_packet::QoS,
+        control_packets::{ControlPacketType, Encodable},
+    };
 
-    use super::PublishPacket;
+    use super::{PublishPacket, PublishPacketFlags};
 
     #[test]
     fn test() {
@@ -196,11 +261,56 @@ mod publish_packet_tests {
             0x74,
         ];
 
-        let publish_packet = PublishPacket::from(publish_packet_bytes.as_slice());
+        let publish_packet = PublishPacket::try_from(publish_packet_bytes.as_slice()).unwrap();
 
         assert_eq!(
             publish_packet.fixed_header.packet_type,
             ControlPacketType::Publish
         );
     }
+
+    #[test]
+    fn flags_test() {
+        let flags = PublishPacketFlags::new(true, QoS::ExactlyOnce, true);
+        assert_eq!(u8::from(flags), 0b0000_1101);
+        assert!(flags.dup());
+        assert_eq!(flags.qos(), QoS::ExactlyOnce);
+        assert!(flags.retain());
+    }
+
+    #[test]
+    fn qos_2_round_trip_test() {
+        let publish_packet_bytes = super::Builder::new()
+            .packet_flags(PublishPacketFlags::new(false, QoS::ExactlyOnce, false))
+            .packet_id(0x1234)
+            .topic_name("a/b")
+            .payload(b"test")
+            .build()
+            .unwrap()
+            .encode();
+
+        let publish_packet = PublishPacket::try_from(publish_packet_bytes.as_slice()).unwrap();
+
+        assert_eq!(publish_packet.flags().qos(), QoS::ExactlyOnce);
+        assert_eq!(publish_packet.packet_id, Some(0x1234));
+        assert_eq!(publish_packet.topic_name, "a/b");
+        assert_eq!(publish_packet.payload, b"test");
+    }
+
+    #[test]
+    fn malformed_test() {
+        for bytes in [
+            // cut short before the topic length
+            &[0x30, 0x01, 0x00][..],
+            // both QoS bits set
+            &[0x36, 0x05, 0x00, 0x03, b'a', b'/', b'b'][..],
+            // the remaining length says more than there is
+            &[0x30, 0x09, 0x00, 0x03, b'a'][..],
+        ] {
+            assert_eq!(
+                PublishPacket::try_from(bytes).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
 }
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::oneshot;
+
+use crate::control_packets::as_u16_be;
+
+// Request/response on top of publish and subscribe.
+// MQTT 3.1.1 has no response topic or correlation data properties, so a request carries both
+// in an envelope in front of its payload, and the response carries the correlation data back:
+//   request:  | response topic length (2) | response topic | correlation data length (2) | correlation data | payload |
+//   response: | correlation data length (2) | correlation data | payload |
+// The lengths are big endian, like every length in MQTT.
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Request {
+    pub response_topic: String,
+    pub correlation_data: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Request {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        put_prefixed(&mut bytes, self.response_topic.as_bytes())?;
+        put_prefixed(&mut bytes, &self.correlation_data)?;
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Request> {
+        let (response_topic, rest) = take_prefixed(bytes)?;
+        let response_topic = std::str::from_utf8(response_topic)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let (correlation_data, payload) = take_prefixed(rest)?;
+        Ok(Request {
+            response_topic: response_topic.to_string(),
+            correlation_data: correlation_data.to_vec(),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Response {
+    pub correlation_data: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl Response {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        put_prefixed(&mut bytes, &self.correlation_data)?;
+        bytes.extend_from_slice(&self.payload);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Response> {
+        let (correlation_data, payload) = take_prefixed(bytes)?;
+        Ok(Response {
+            correlation_data: correlation_data.to_vec(),
+            payload: payload.to_vec(),
+        })
+    }
+}
+
+fn put_prefixed(bytes: &mut Vec<u8>, field: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(field.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "envelope fields are limited to 65535 bytes",
+        )
+    })?;
+    bytes.extend_from_slice(&length.to_be_bytes());
+    bytes.extend_from_slice(field);
+    Ok(())
+}
+
+fn take_prefixed(bytes: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated envelope");
+    if bytes.len() < 2 {
+        return Err(truncated());
+    }
+    let length = as_u16_be(&bytes[..2]) as usize;
+    let rest = &bytes[2..];
+    if rest.len() < length {
+        return Err(truncated());
+    }
+    Ok(rest.split_at(length))
+}
+
+// The requests of one client that wait for their response
+#[derive(Debug)]
+pub(crate) struct PendingRequests {
+    // every response topic starts with this, the client subscribes to prefix/+
+    reply_prefix: String,
+    last_correlation: u64,
+    waiting: HashMap<Vec<u8>, oneshot::Sender<Vec<u8>>>,
+    // set once the reply handler is registered with the router
+    pub handler_registered: bool,
+    // cleared when a connect doesn't resume the session, the Server forgot the subscription then
+    pub subscribed: bool,
+}
+
+impl PendingRequests {
+    pub fn new(client_id: &str) -> Self {
+        PendingRequests {
+            reply_prefix: reply_prefix(client_id),
+            last_correlation: 0,
+            waiting: HashMap::new(),
+            handler_registered: false,
+            subscribed: false,
+        }
+    }
+
+    pub fn reply_filter(&self) -> String {
+        format!("{}/+", self.reply_prefix)
+    }
+
+    // Returns the request to send and the receiver its response payload arrives on
+    pub fn register(&mut self, payload: &[u8]) -> (Request, oneshot::Receiver<Vec<u8>>) {
+        self.last_correlation += 1;
+        let correlation_data = self.last_correlation.to_be_bytes().to_vec();
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.insert(correlation_data.clone(), sender);
+        let request = Request {
+            response_topic: format!("{}/{}", self.reply_prefix, self.last_correlation),
+            correlation_data,
+            payload: payload.to_vec(),
+        };
+        (request, receiver)
+    }
+
+    // Returns false if nobody waits for the response, e.g. because its request timed out
+    pub fn complete(&mut self, response: Response) -> bool {
+        match self.waiting.remove(&response.correlation_data) {
+            Some(sender) => sender.send(response.payload).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn abandon(&mut self, correlation_data: &[u8]) {
+        self.waiting.remove(correlation_data);
+    }
+}
+
+// The client id keeps the response topics of different clients apart. Ids that can't be used
+// as a topic level, like the empty one the Server assigns an id for, are replaced with a nonce.
+fn reply_prefix(client_id: &str) -> String {
+    let id = match !client_id.is_empty() && !client_id.contains(['/', '+', '#']) {
+        true => client_id.to_string(),
+        false => {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_nanos())
+                .unwrap_or_default();
+            format!("{:x}{:x}", std::process::id(), nanos)
+        }
+    };
+    format!("mqutekitty/replies/{}", id)
+}
+
+#[cfg(test)]
+mod request_tests {
+    use std::io;
+
+    use super::{PendingRequests, Request, Response};
+
+    #[test]
+    fn envelope_test() {
+        let request = Request {
+            response_topic: "r/1".to_string(),
+            correlation_data: vec![7],
+            payload: b"ping".to_vec(),
+        };
+        let bytes = request.encode().unwrap();
+        assert_eq!(bytes, [&[0, 3][..], b"r/1", &[0, 1, 7], b"ping"].concat());
+        assert_eq!(Request::decode(&bytes).unwrap(), request);
+        assert_eq!(
+            Request::decode(&bytes[..4]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let response = Response {
+            correlation_data: vec![7],
+            payload: b"pong".to_vec(),
+        };
+        let bytes = response.encode().unwrap();
+        assert_eq!(bytes, [&[0, 1, 7][..], b"pong"].concat());
+        assert_eq!(Response::decode(&bytes).unwrap(), response);
+    }
+
+    #[test]
+    fn pending_requests_test() {
+        let mut requests = PendingRequests::new("kitty");
+        assert_eq!(requests.reply_filter(), "mqutekitty/replies/kitty/+");
+
+        let (first, mut first_response) = requests.register(b"a");
+        let (second, mut second_response) = requests.register(b"b");
+        assert_eq!(first.response_topic, "mqutekitty/replies/kitty/1");
+        assert_ne!(first.correlation_data, second.correlation_data);
+
+        assert!(requests.complete(Response {
+            correlation_data: second.correlation_data.clone(),
+            payload: b"second".to_vec(),
+        }));
+        assert_eq!(second_response.try_recv().unwrap(), b"second");
+
+        requests.abandon(&first.correlation_data);
+        assert!(first_response.try_recv().is_err());
+        assert!(!requests.complete(Response {
+            correlation_data: first.correlation_data,
+            payload: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn reply_prefix_test() {
+        let requests = PendingRequests::new("");
+        assert!(requests.reply_filter().starts_with("mqutekitty/replies/"));
+        assert_ne!(requests.reply_filter(), "mqutekitty/replies//+");
+        let requests = PendingRequests::new("a/#");
+        assert!(!requests.reply_filter().contains("a/#"));
+    }
+}
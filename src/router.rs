@@ -0,0 +1,377 @@
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use tokio::runtime::Handle;
+
+use crate::publish_packet::Message;
+
+// 4.7. Topic Names and Topic Filters
+// The forward slash ('/') is used to separate each level within a topic tree.
+// The number sign ('#') is a wildcard character that matches any number of levels within a topic.
+// The multi-level wildcard character MUST be specified either on its own or following a topic level separator.
+// In either case it MUST be the last character specified in the Topic Filter [MQTT-4.7.1-2].
+// The plus sign ('+') is a wildcard character that matches only one topic level.
+// The single-level wildcard can be used at any level in the Topic Filter, including first and last levels.
+// Where it is used it MUST occupy an entire level of the filter [MQTT-4.7.1-3].
+// The Server MUST NOT match Topic Filters starting with a wildcard character (# or +)
+// with Topic Names beginning with a $ character [MQTT-4.7.2-1].
+
+#[derive(Debug, Clone)]
+pub struct InvalidTopicFilter;
+impl fmt::Display for InvalidTopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid topic filter")
+    }
+}
+
+impl Error for InvalidTopicFilter {}
+
+pub fn is_valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    let last_index = levels.len() - 1;
+    levels
+        .iter()
+        .enumerate()
+        .all(|(index, level)| match *level {
+            "#" => index == last_index,
+            "+" => true,
+            _ => !level.contains(['#', '+']),
+        })
+}
+
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['#', '+']) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            // "sport/#" also matches "sport", the parent level
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => continue,
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[derive(Clone)]
+enum Handler {
+    // runs inside the connection task, which can't send keep alives or acknowledgements for any
+    // handle until it returns. Slow work belongs in an Async handler, see ClientHandle::on_async
+    Sync(Arc<dyn Fn(&Message) + Send + Sync>),
+    // spawned on the runtime it was registered from
+    Async(Arc<dyn Fn(Message) -> HandlerFuture + Send + Sync>, Handle),
+}
+
+impl Handler {
+    fn call(&self, message: &Message) {
+        match self {
+            Handler::Sync(handler) => handler(message),
+            Handler::Async(handler, runtime) => {
+                runtime.spawn(handler(message.clone()));
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct HandlerId(u64);
+
+#[derive(Default)]
+pub struct Router {
+    handlers: Vec<(HandlerId, String, Handler)>,
+    catch_all: Option<Handler>,
+    // get a copy of every message no filter matched, next to the catch-all
+    unmatched_listeners: Vec<Sender<Message>>,
+    last_handler_id: u64,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn add<F>(&mut self, filter: &str, handler: F) -> Result<HandlerId, InvalidTopicFilter>
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        self.insert(filter, Handler::Sync(Arc::new(handler)))
+    }
+
+    // Must be called from within a tokio runtime, that's where the handler futures are spawned
+    pub fn add_async<F, Fut>(
+        &mut self,
+        filter: &str,
+        handler: F,
+    ) -> Result<HandlerId, InvalidTopicFilter>
+    where
+        F: Fn(Message) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler = move |message: Message| -> HandlerFuture { Box::pin(handler(message)) };
+        self.insert(filter, Handler::Async(Arc::new(handler), Handle::current()))
+    }
+
+    // Receives every message that no filter matched
+    pub fn set_catch_all<F>(&mut self, handler: F)
+    where
+        F: Fn(&Message) + Send + Sync + 'static,
+    {
+        self.catch_all = Some(Handler::Sync(Arc::new(handler)));
+    }
+
+    pub fn clear_catch_all(&mut self) {
+        self.catch_all = None;
+    }
+
+    // Receives every message that no filter matched, whatever catch-all is set.
+    // The listener is dropped once the receiver is.
+    pub fn listen_unmatched(&mut self) -> Receiver<Message> {
+        let (sender, receiver) = mpsc::channel();
+        self.unmatched_listeners.push(sender);
+        receiver
+    }
+
+    // Returns the filter of the removed handler, and whether it was the last handler for that filter
+    pub fn remove(&mut self, handler_id: HandlerId) -> Option<(String, bool)> {
+        let index = self
+            .handlers
+            .iter()
+            .position(|(id, _, _)| *id == handler_id)?;
+        let (_, filter, _) = self.handlers.remove(index);
+        let was_last = !self.handlers.iter().any(|(_, other, _)| *other == filter);
+        Some((filter, was_last))
+    }
+
+    pub fn handler_count(&self) -> usize {
+        self.handlers.len()
+    }
+
+    // Returns the handlers the message has to be delivered to, so they can be called
+    // without holding on to the router. None means that nothing, not even a catch-all, wants it.
+    // Unmatched messages are sent to the listeners right away.
+    fn handlers_for(&mut self, message: &Message) -> Option<Vec<Handler>> {
+        let matching: Vec<Handler> = self
+            .handlers
+            .iter()
+            .filter(|(_, filter, _)| topic_matches(filter, &message.topic))
+            .map(|(_, _, handler)| handler.clone())
+            .collect();
+        if !matching.is_empty() {
+            return Some(matching);
+        }
+        self.unmatched_listeners
+            .retain(|listener| listener.send(message.clone()).is_ok());
+        match &self.catch_all {
+            Some(handler) => Some(vec![handler.clone()]),
+            None if !self.unmatched_listeners.is_empty() => Some(Vec::new()),
+            None => None,
+        }
+    }
+
+    fn insert(&mut self, filter: &str, handler: Handler) -> Result<HandlerId, InvalidTopicFilter> {
+        if !is_valid_topic_filter(filter) {
+            return Err(InvalidTopicFilter);
+        }
+        self.last_handler_id += 1;
+        let handler_id = HandlerId(self.last_handler_id);
+        self.handlers
+            .push((handler_id, filter.to_string(), handler));
+        Ok(handler_id)
+    }
+}
+
+// Delivers the message to every matching handler, or to the catch-all and the listeners.
+// Returns false if nobody took the message.
+pub fn route(router: &Mutex<Router>, message: &Message) -> bool {
+    // the lock is released before calling out, so handlers can register or remove handlers themselves
+    let handlers = router.lock().unwrap().handlers_for(message);
+    match handlers {
+        Some(handlers) => {
+            for handler in handlers {
+                handler.call(message);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod router_tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use crate::{connect_packet::QoS, publish_packet::Message};
+
+    use super::{is_valid_topic_filter, route, topic_matches, Router};
+
+    fn message(topic: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: b"21.5".to_vec(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn topic_matches_test() {
+        assert!(topic_matches("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(!topic_matches("sensors/+/temp", "sensors/kitchen/humidity"));
+        assert!(!topic_matches(
+            "sensors/+/temp",
+            "sensors/kitchen/oven/temp"
+        ));
+        assert!(topic_matches("sensors/#", "sensors/kitchen/oven/temp"));
+        assert!(topic_matches("sensors/#", "sensors"));
+        assert!(topic_matches("#", "sensors/kitchen"));
+        assert!(topic_matches("+/+", "/finance"));
+        assert!(!topic_matches("+", "/finance"));
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(!topic_matches("a/b/c", "a/b"));
+    }
+
+    #[test]
+    fn system_topics_test() {
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(!topic_matches("+/uptime", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+
+    #[test]
+    fn valid_topic_filter_test() {
+        assert!(is_valid_topic_filter("sensors/+/temp"));
+        assert!(is_valid_topic_filter("sensors/#"));
+        assert!(is_valid_topic_filter("#"));
+        assert!(!is_valid_topic_filter(""));
+        assert!(!is_valid_topic_filter("sensors/#/temp"));
+        assert!(!is_valid_topic_filter("sensors+"));
+        assert!(!is_valid_topic_filter("sensors/te#"));
+    }
+
+    #[test]
+    fn listen_unmatched_test() {
+        let router = Mutex::new(Router::new());
+        let listener = router.lock().unwrap().listen_unmatched();
+        router.lock().unwrap().add("sensors/#", |_| {}).unwrap();
+
+        assert!(route(&router, &message("sensors/kitchen")));
+        assert!(route(&router, &message("alerts/fire")));
+        // a catch-all set later doesn't take the messages away from the listener
+        let caught = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&caught);
+        router.lock().unwrap().set_catch_all(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(route(&router, &message("alerts/flood")));
+
+        let topics: Vec<String> = listener.try_iter().map(|message| message.topic).collect();
+        assert_eq!(topics, vec!["alerts/fire", "alerts/flood"]);
+        assert_eq!(caught.load(Ordering::SeqCst), 1);
+
+        drop(listener);
+        router.lock().unwrap().clear_catch_all();
+        assert!(!route(&router, &message("alerts/fire")));
+    }
+
+    #[test]
+    fn route_test() {
+        let router = Mutex::new(Router::new());
+        let temperatures = Arc::new(AtomicUsize::new(0));
+        let everything = Arc::new(AtomicUsize::new(0));
+        let unmatched = Arc::new(AtomicUsize::new(0));
+        {
+            let mut router = router.lock().unwrap();
+            let counter = Arc::clone(&temperatures);
+            router
+                .add("sensors/+/temp", move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            let counter = Arc::clone(&everything);
+            router
+                .add("sensors/#", move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            let counter = Arc::clone(&unmatched);
+            router.set_catch_all(move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        assert!(route(&router, &message("sensors/kitchen/temp")));
+        assert!(route(&router, &message("sensors/kitchen/humidity")));
+        assert!(route(&router, &message("alerts/fire")));
+
+        assert_eq!(temperatures.load(Ordering::SeqCst), 1);
+        assert_eq!(everything.load(Ordering::SeqCst), 2);
+        assert_eq!(unmatched.load(Ordering::SeqCst), 1);
+
+        router.lock().unwrap().clear_catch_all();
+        assert!(!route(&router, &message("alerts/fire")));
+    }
+
+    #[test]
+    fn remove_test() {
+        let mut router = Router::new();
+        let first = router.add("a/+", |_| {}).unwrap();
+        let second = router.add("a/+", |_| {}).unwrap();
+        let other = router.add("b", |_| {}).unwrap();
+
+        assert_eq!(router.remove(first), Some((String::from("a/+"), false)));
+        assert_eq!(router.remove(second), Some((String::from("a/+"), true)));
+        assert_eq!(router.remove(second), None);
+        assert_eq!(router.remove(other), Some((String::from("b"), true)));
+        assert_eq!(router.handler_count(), 0);
+    }
+
+    #[test]
+    fn invalid_filter_test() {
+        let mut router = Router::new();
+        assert!(router.add("a/#/b", |_| {}).is_err());
+    }
+
+    #[tokio::test]
+    async fn async_handler_test() {
+        let router = Mutex::new(Router::new());
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        router
+            .lock()
+            .unwrap()
+            .add_async("sensors/+/temp", move |message| {
+                let sender = sender.clone();
+                async move {
+                    sender.send(message.topic).unwrap();
+                }
+            })
+            .unwrap();
+
+        assert!(route(&router, &message("sensors/kitchen/temp")));
+        assert_eq!(
+            receiver.recv().await,
+            Some(String::from("sensors/kitchen/temp"))
+        );
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt, io,
+};
+
+use tracing::{error, warn};
+
+use crate::{
+    connect_packet::QoS,
+    control_packets::Encodable,
+    packet_id::{PacketIdAllocator, PacketIdsExhausted},
+    pub_ack_packets::{PubCompPacket, PubRelPacket},
+    publish_packet::{self, Message, PublishPacketFlags},
+    session_store::SessionStore,
+};
+
+// 4.1. Storing state
+// It is necessary for the Client and Server to store Session state in order to provide
+// Quality of Service guarantees. The Client's Session state consists of:
+//   - QoS 1 and QoS 2 messages which have been sent to the Server, but have not been completely acknowledged.
+//   - QoS 2 messages which have been received from the Server, but have not been completely acknowledged.
+//
+// The state lives in the client, not in the connection, so that it survives a reconnect
+// when the Server reports that it kept the Session (CleanSession = 0, Session Present = 1).
+// With a SessionStore it also survives a restart of the process.
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutgoingState {
+    // QoS 1: PUBLISH sent, waiting for PUBACK
+    AwaitingPubAck,
+    // QoS 2: PUBLISH sent, waiting for PUBREC
+    AwaitingPubRec,
+    // QoS 2: PUBREL sent, waiting for PUBCOMP
+    AwaitingPubComp,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct OutgoingPublish {
+    pub packet_id: u16,
+    pub message: Message,
+    pub state: OutgoingState,
+}
+
+impl OutgoingPublish {
+    // Encodes the packet that moves this message forward: the PUBLISH itself while it hasn't been
+    // acknowledged yet, or the PUBREL once the Server has sent PUBREC.
+    pub fn encode(&self, dup: bool) -> Vec<u8> {
+        match self.state {
+            OutgoingState::AwaitingPubAck | OutgoingState::AwaitingPubRec => {
+                publish_packet::Builder::new()
+                    .packet_flags(PublishPacketFlags::new(
+                        dup,
+                        self.message.qos,
+                        self.message.retain,
+                    ))
+                    .packet_id(self.packet_id)
+                    .topic_name(&self.message.topic)
+                    .payload(&self.message.payload)
+                    .build()
+                    .unwrap()
+                    .encode()
+            }
+            OutgoingState::AwaitingPubComp => PubRelPacket::new(self.packet_id).encode(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct SessionState {
+    // kept in the order the messages were published, so a resume retransmits them in order [MQTT-4.6.0-1]
+    outgoing: VecDeque<OutgoingPublish>,
+    // ids of QoS 2 messages that were delivered to the application, but not yet released by the Server
+    incoming: HashSet<u16>,
+    // ids used by our in-flight PUBLISH, SUBSCRIBE and UNSUBSCRIBE packets
+    packet_ids: PacketIdAllocator,
+    // every change to outgoing and incoming is mirrored here
+    store: Option<Box<dyn SessionStore>>,
+}
+
+impl fmt::Debug for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SessionState")
+            .field("outgoing", &self.outgoing)
+            .field("incoming", &self.incoming)
+            .field("packet_ids", &self.packet_ids)
+            .field("persistent", &self.store.is_some())
+            .finish()
+    }
+}
+
+impl SessionState {
+    pub fn new() -> Self {
+        SessionState::default()
+    }
+
+    // Restores whatever the store kept from a previous run. The restored messages are
+    // retransmitted by resume, once the Server has answered the CONNECT.
+    pub fn with_store(mut store: Box<dyn SessionStore>) -> io::Result<Self> {
+        let stored = store.load()?;
+        let mut session = SessionState::new();
+        for outgoing in stored.outgoing {
+            if !session.packet_ids.reserve(outgoing.packet_id) {
+                warn!(
+                    "Dropping stored message with duplicate packet id {}",
+                    outgoing.packet_id
+                );
+                continue;
+            }
+            session.outgoing.push_back(outgoing);
+        }
+        session.incoming.extend(stored.incoming);
+        session.store = Some(store);
+        Ok(session)
+    }
+
+    pub fn acquire_packet_id(&mut self) -> Result<u16, PacketIdsExhausted> {
+        self.packet_ids.acquire()
+    }
+
+    // Only needed for SUBSCRIBE and UNSUBSCRIBE, publish ids are released by their acknowledgement
+    pub fn release_packet_id(&mut self, packet_id: u16) {
+        self.packet_ids.release(packet_id);
+    }
+
+    pub fn outgoing_count(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    pub fn incoming_count(&self) -> usize {
+        self.incoming.len()
+    }
+
+    // Records a QoS 1 or QoS 2 message that is about to be sent and returns the encoded PUBLISH.
+    // The packet id has to come from acquire_packet_id.
+    // Fails if the message couldn't be persisted, in which case it isn't recorded at all.
+    pub fn publish(&mut self, packet_id: u16, message: Message) -> io::Result<Vec<u8>> {
+        let state = match message.qos {
+            QoS::AtLeastOnce => OutgoingState::AwaitingPubAck,
+            QoS::ExactlyOnce => OutgoingState::AwaitingPubRec,
+            QoS::AtMostOnce => panic!("QoS 0 messages have no session state"),
+        };
+        let outgoing = OutgoingPublish {
+            packet_id,
+            message,
+            state,
+        };
+        if let Some(store) = &mut self.store {
+            store.put_outgoing(&outgoing)?;
+        }
+        let bytes = outgoing.encode(false);
+        self.outgoing.push_back(outgoing);
+        Ok(bytes)
+    }
+
+    pub fn handle_pub_ack(&mut self, packet_id: u16) -> Option<OutgoingPublish> {
+        self.complete(packet_id, OutgoingState::AwaitingPubAck)
+    }
+
+    // Returns the PUBREL to answer with. A duplicate PUBREC for a message that is already
+    // waiting for PUBCOMP is answered with another PUBREL.
+    pub fn handle_pub_rec(&mut self, packet_id: u16) -> Option<PubRelPacket> {
+        match self
+            .outgoing
+            .iter_mut()
+            .find(|outgoing| outgoing.packet_id == packet_id)
+        {
+            Some(outgoing) if outgoing.state != OutgoingState::AwaitingPubAck => {
+                if outgoing.state != OutgoingState::AwaitingPubComp {
+                    outgoing.state = OutgoingState::AwaitingPubComp;
+                    if let Some(store) = &mut self.store {
+                        persist(store.put_outgoing(outgoing));
+                    }
+                }
+                Some(PubRelPacket::new(packet_id))
+            }
+            _ => {
+                warn!("Received a PubRec for unknown packet id {}", packet_id);
+                None
+            }
+        }
+    }
+
+    pub fn handle_pub_comp(&mut self, packet_id: u16) -> Option<OutgoingPublish> {
+        self.complete(packet_id, OutgoingState::AwaitingPubComp)
+    }
+
+    // Returns true the first time a QoS 2 packet id is seen, i.e. when the message has to be
+    // delivered to the application. Redelivered duplicates return false until the PUBREL arrives.
+    pub fn handle_incoming_qos2(&mut self, packet_id: u16) -> bool {
+        let first_time = self.incoming.insert(packet_id);
+        if first_time {
+            if let Some(store) = &mut self.store {
+                persist(store.put_incoming(packet_id));
+            }
+        }
+        first_time
+    }
+
+    pub fn handle_pub_rel(&mut self, packet_id: u16) -> PubCompPacket {
+        if self.incoming.remove(&packet_id) {
+            if let Some(store) = &mut self.store {
+                persist(store.remove_incoming(packet_id));
+            }
+        }
+        PubCompPacket::new(packet_id)
+    }
+
+    // Called after every CONNACK. Returns the packets that have to be retransmitted, in order.
+    //
+    // When the Server kept the Session, unacknowledged PUBLISH packets are resent with the DUP flag
+    // and pending PUBRELs are resent as they are [MQTT-4.4.0-1].
+    // When it didn't, the Server has no record of our packet ids any more: pending PUBRELs are
+    // dropped (the Server already owns those messages), unacknowledged messages are published
+    // again from scratch, and the incoming ids are forgotten.
+    pub fn resume(&mut self, session_present: bool) -> Vec<Vec<u8>> {
+        if !session_present {
+            if let Some(store) = &mut self.store {
+                for packet_id in self.incoming.iter() {
+                    persist(store.remove_incoming(*packet_id));
+                }
+            }
+            self.incoming.clear();
+            let packet_ids = &mut self.packet_ids;
+            let store = &mut self.store;
+            self.outgoing.retain(|outgoing| {
+                let keep = outgoing.state != OutgoingState::AwaitingPubComp;
+                if !keep {
+                    packet_ids.release(outgoing.packet_id);
+                    if let Some(store) = store {
+                        persist(store.remove_outgoing(outgoing.packet_id));
+                    }
+                }
+                keep
+            });
+        }
+
+        self.outgoing
+            .iter()
+            .map(|outgoing| outgoing.encode(session_present))
+            .collect()
+    }
+
+    fn complete(&mut self, packet_id: u16, expected: OutgoingState) -> Option<OutgoingPublish> {
+        let index = self
+            .outgoing
+            .iter()
+            .position(|outgoing| outgoing.packet_id == packet_id && outgoing.state == expected);
+        match index {
+            Some(index) => {
+                self.packet_ids.release(packet_id);
+                if let Some(store) = &mut self.store {
+                    persist(store.remove_outgoing(packet_id));
+                }
+                self.outgoing.remove(index)
+            }
+            None => {
+                warn!(
+                    "Received an acknowledgement for unknown packet id {}",
+                    packet_id
+                );
+                None
+            }
+        }
+    }
+}
+
+// The protocol has already moved on when an acknowledgement is persisted, so a failure can only
+// be reported. At worst a message is retransmitted after a restart, which QoS 1 and 2 allow.
+fn persist(result: io::Result<()>) {
+    if let Err(persist_error) = result {
+        error!("Failed to persist the session state: {}", persist_error);
+    }
+}
+
+#[cfg(test)]
+mod session_state_tests {
+    use crate::{
+        connect_packet::QoS,
+        control_packets::Encodable,
+        publish_packet::{Message, PublishPacket},
+        session_store::{MemorySessionStore, SessionStore},
+    };
+
+    use super::{OutgoingState, SessionState};
+
+    fn message(qos: QoS) -> Message {
+        Message {
+            topic: String::from("billing/events"),
+            payload: b"42".to_vec(),
+            qos,
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn outgoing_qos2_flow_test() {
+        let mut session = SessionState::new();
+        let packet_id = session.acquire_packet_id().unwrap();
+        session
+            .publish(packet_id, message(QoS::ExactlyOnce))
+            .unwrap();
+        assert_eq!(session.outgoing_count(), 1);
+
+        // not a QoS 1 message, so a PUBACK doesn't complete it
+        assert!(session.handle_pub_ack(packet_id).is_none());
+
+        let pub_rel = session.handle_pub_rec(packet_id).unwrap();
+        assert_eq!(pub_rel.encode(), vec![0b0110_0010, 2, 0, 1]);
+        assert_eq!(session.outgoing_count(), 1);
+
+        assert!(session.handle_pub_comp(packet_id).is_some());
+        assert_eq!(session.outgoing_count(), 0);
+        assert!(!session.packet_ids.is_in_flight(packet_id));
+    }
+
+    #[test]
+    fn outgoing_qos1_flow_test() {
+        let mut session = SessionState::new();
+        let packet_id = session.acquire_packet_id().unwrap();
+        session
+            .publish(packet_id, message(QoS::AtLeastOnce))
+            .unwrap();
+        assert!(session.handle_pub_rec(packet_id).is_none());
+        assert!(session.handle_pub_ack(packet_id).is_some());
+        assert_eq!(session.outgoing_count(), 0);
+        assert!(!session.packet_ids.is_in_flight(packet_id));
+    }
+
+    #[test]
+    fn incoming_qos2_duplicate_test() {
+        let mut session = SessionState::new();
+        assert!(session.handle_incoming_qos2(9));
+        // the Server resends the PUBLISH because our PUBREC got lost
+        assert!(!session.handle_incoming_qos2(9));
+
+        let pub_comp = session.handle_pub_rel(9);
+        assert_eq!(pub_comp.packet_id, 9);
+        assert_eq!(session.incoming_count(), 0);
+
+        // the id is free to be reused for a new message
+        assert!(session.handle_incoming_qos2(9));
+    }
+
+    #[test]
+    fn resume_with_session_test() {
+        let mut session = SessionState::new();
+        for _ in 0..2 {
+            let packet_id = session.acquire_packet_id().unwrap();
+            session
+                .publish(packet_id, message(QoS::ExactlyOnce))
+                .unwrap();
+        }
+        session.handle_pub_rec(2);
+        session.handle_incoming_qos2(3);
+
+        let retransmissions = session.resume(true);
+
+        assert_eq!(retransmissions.len(), 2);
+        let publish = PublishPacket::try_from(retransmissions[0].as_slice()).unwrap();
+        assert!(publish.flags().dup());
+        assert_eq!(publish.packet_id, Some(1));
+        assert_eq!(retransmissions[1], vec![0b0110_0010, 2, 0, 2]);
+        // the broker still remembers the incoming message, so it must not be delivered again
+        assert!(!session.handle_incoming_qos2(3));
+    }
+
+    #[test]
+    fn resume_without_session_test() {
+        let mut session = SessionState::new();
+        for _ in 0..2 {
+            let packet_id = session.acquire_packet_id().unwrap();
+            session
+                .publish(packet_id, message(QoS::ExactlyOnce))
+                .unwrap();
+        }
+        session.handle_pub_rec(2);
+        session.handle_incoming_qos2(3);
+
+        let retransmissions = session.resume(false);
+
+        assert_eq!(retransmissions.len(), 1);
+        let publish = PublishPacket::try_from(retransmissions[0].as_slice()).unwrap();
+        assert!(!publish.flags().dup());
+        assert_eq!(publish.packet_id, Some(1));
+        assert_eq!(session.incoming_count(), 0);
+        // the dropped PUBREL gave its id back
+        assert!(!session.packet_ids.is_in_flight(2));
+    }
+
+    #[test]
+    fn restore_from_store_test() {
+        let mut store = MemorySessionStore::new();
+        let mut session = SessionState::new();
+        session.store = Some(Box::new(MemorySessionStore::new()));
+        for _ in 0..3 {
+            let packet_id = session.acquire_packet_id().unwrap();
+            session
+                .publish(packet_id, message(QoS::ExactlyOnce))
+                .unwrap();
+        }
+        session.handle_pub_rec(2);
+        session.handle_pub_rec(3);
+        session.handle_pub_comp(3);
+        session.handle_incoming_qos2(7);
+        // the process goes down, the store is all that's left
+        let stored = session.store.take().unwrap().load().unwrap();
+        for outgoing in stored.outgoing.iter() {
+            store.put_outgoing(outgoing).unwrap();
+        }
+        for packet_id in stored.incoming {
+            store.put_incoming(packet_id).unwrap();
+        }
+
+        let mut session = SessionState::with_store(Box::new(store)).unwrap();
+
+        assert_eq!(session.outgoing_count(), 2);
+        assert!(session.packet_ids.is_in_flight(1));
+        assert!(session.packet_ids.is_in_flight(2));
+        assert_eq!(session.acquire_packet_id().unwrap(), 3);
+        assert!(!session.handle_incoming_qos2(7));
+        let retransmissions = session.resume(true);
+        assert_eq!(retransmissions.len(), 2);
+        assert_eq!(retransmissions[1], vec![0b0110_0010, 2, 0, 2]);
+
+        session.handle_pub_comp(2);
+        session.handle_pub_rel(7);
+        let stored = session.store.as_mut().unwrap().load().unwrap();
+        assert_eq!(stored.outgoing.len(), 1);
+        assert_eq!(stored.outgoing[0].packet_id, 1);
+        assert_eq!(stored.outgoing[0].state, OutgoingState::AwaitingPubRec);
+        assert!(stored.incoming.is_empty());
+    }
+}
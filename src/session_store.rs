@@ -0,0 +1,510 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing::warn;
+
+use crate::{
+    connect_packet::QoS,
+    publish_packet::Message,
+    session_state::{OutgoingPublish, OutgoingState},
+};
+
+// Persistence for the Session state, so that unacknowledged QoS 1 and QoS 2 messages and
+// pending PUBRELs survive a restart of the process when the Session is kept (CleanSession = 0).
+// The store mirrors every change the SessionState makes, and is read back once when the client starts.
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct StoredSession {
+    // in the order they were published
+    pub outgoing: Vec<OutgoingPublish>,
+    pub incoming: Vec<u16>,
+}
+
+pub trait SessionStore: Send {
+    // Inserts a message, or updates its state if the packet id is already stored.
+    // Updating keeps the message in its original position.
+    fn put_outgoing(&mut self, outgoing: &OutgoingPublish) -> io::Result<()>;
+    fn remove_outgoing(&mut self, packet_id: u16) -> io::Result<()>;
+    fn put_incoming(&mut self, packet_id: u16) -> io::Result<()>;
+    fn remove_incoming(&mut self, packet_id: u16) -> io::Result<()>;
+    fn load(&mut self) -> io::Result<StoredSession>;
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+// Keeps the session for as long as the store lives, e.g. across several clients in one process
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    outgoing: VecDeque<OutgoingPublish>,
+    incoming: BTreeSet<u16>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        MemorySessionStore::default()
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn put_outgoing(&mut self, outgoing: &OutgoingPublish) -> io::Result<()> {
+        match self
+            .outgoing
+            .iter_mut()
+            .find(|stored| stored.packet_id == outgoing.packet_id)
+        {
+            Some(stored) => *stored = outgoing.clone(),
+            None => self.outgoing.push_back(outgoing.clone()),
+        }
+        Ok(())
+    }
+
+    fn remove_outgoing(&mut self, packet_id: u16) -> io::Result<()> {
+        self.outgoing.retain(|stored| stored.packet_id != packet_id);
+        Ok(())
+    }
+
+    fn put_incoming(&mut self, packet_id: u16) -> io::Result<()> {
+        self.incoming.insert(packet_id);
+        Ok(())
+    }
+
+    fn remove_incoming(&mut self, packet_id: u16) -> io::Result<()> {
+        self.incoming.remove(&packet_id);
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<StoredSession> {
+        Ok(StoredSession {
+            outgoing: self.outgoing.iter().cloned().collect(),
+            incoming: self.incoming.iter().copied().collect(),
+        })
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.outgoing.clear();
+        self.incoming.clear();
+        Ok(())
+    }
+}
+
+// Journal record layout, all integers big endian
+//  ----------------------------------------------------------------------------------------------
+// | PUT_OUTGOING    | packet id (2) | state (1) | qos (1) | retain (1) | topic length (2) | topic |
+// |                 | payload length (4) | payload                                               |
+//  ----------------------------------------------------------------------------------------------
+// | REMOVE_OUTGOING | packet id (2)                                                              |
+// | PUT_INCOMING    | packet id (2)                                                              |
+// | REMOVE_INCOMING | packet id (2)                                                              |
+//  ----------------------------------------------------------------------------------------------
+const PUT_OUTGOING: u8 = 1;
+const REMOVE_OUTGOING: u8 = 2;
+const PUT_INCOMING: u8 = 3;
+const REMOVE_INCOMING: u8 = 4;
+
+// The journal is compacted once it holds at least this many records
+const MIN_COMPACTION_RECORDS: usize = 1024;
+// and this many times more dead records than live ones
+const COMPACTION_RATIO: usize = 4;
+
+// Appends every change to a journal file and syncs it to disk before returning.
+// Loading replays the journal and rewrites it compacted, and so does an append once most of
+// the journal is records that were overwritten or removed. A record that was only partly
+// written when the process died is ignored.
+#[derive(Debug)]
+pub struct FileSessionStore {
+    path: PathBuf,
+    file: File,
+    // counted since the last load, compaction or clear
+    records: usize,
+    // the packet ids that have a live record
+    outgoing: BTreeSet<u16>,
+    incoming: BTreeSet<u16>,
+}
+
+impl FileSessionStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(FileSessionStore {
+            path,
+            file,
+            records: 0,
+            outgoing: BTreeSet::new(),
+            incoming: BTreeSet::new(),
+        })
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(record)?;
+        self.file.sync_data()?;
+        self.records += 1;
+
+        let live = self.outgoing.len() + self.incoming.len();
+        if self.records >= MIN_COMPACTION_RECORDS && self.records - live > COMPACTION_RATIO * live {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the journal with only what's still alive, through a temporary file so a crash
+    // in the middle doesn't lose the session
+    fn compact(&mut self) -> io::Result<StoredSession> {
+        let mut bytes: Vec<u8> = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut bytes)?;
+        let stored = FileSessionStore::replay(&bytes).load()?;
+
+        let mut compacted: Vec<u8> = Vec::new();
+        for outgoing in stored.outgoing.iter() {
+            compacted.extend_from_slice(&outgoing_record(outgoing)?);
+        }
+        for packet_id in stored.incoming.iter() {
+            compacted.extend_from_slice(&packet_id_record(PUT_INCOMING, *packet_id));
+        }
+        let compacted_path = self.path.with_extension("compacting");
+        {
+            let mut compacted_file = File::create(&compacted_path)?;
+            compacted_file.write_all(&compacted)?;
+            compacted_file.sync_data()?;
+        }
+        fs::rename(&compacted_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+
+        self.outgoing = stored
+            .outgoing
+            .iter()
+            .map(|stored| stored.packet_id)
+            .collect();
+        self.incoming = stored.incoming.iter().copied().collect();
+        self.records = self.outgoing.len() + self.incoming.len();
+        Ok(stored)
+    }
+
+    fn replay(bytes: &[u8]) -> MemorySessionStore {
+        let mut session = MemorySessionStore::new();
+        let mut reader = bytes;
+        while !reader.is_empty() {
+            match FileSessionStore::replay_record(&mut reader, &mut session) {
+                Ok(_) => {}
+                Err(error) => {
+                    warn!("Ignoring the end of the session journal: {}", error);
+                    break;
+                }
+            }
+        }
+        session
+    }
+
+    fn replay_record(reader: &mut &[u8], session: &mut MemorySessionStore) -> io::Result<()> {
+        let record_type = read_u8(reader)?;
+        let packet_id = read_u16(reader)?;
+        match record_type {
+            PUT_OUTGOING => {
+                let state = match read_u8(reader)? {
+                    0 => OutgoingState::AwaitingPubAck,
+                    1 => OutgoingState::AwaitingPubRec,
+                    _ => OutgoingState::AwaitingPubComp,
+                };
+                let qos = QoS::from(read_u8(reader)?);
+                let retain = read_u8(reader)? != 0;
+                let topic_length = read_u16(reader)? as usize;
+                let topic = String::from_utf8(read_bytes(reader, topic_length)?)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                let payload_length = read_u32(reader)? as usize;
+                let payload = read_bytes(reader, payload_length)?;
+                session.put_outgoing(&OutgoingPublish {
+                    packet_id,
+                    message: Message {
+                        topic,
+                        payload,
+                        qos,
+                        retain,
+                    },
+                    state,
+                })
+            }
+            REMOVE_OUTGOING => session.remove_outgoing(packet_id),
+            PUT_INCOMING => session.put_incoming(packet_id),
+            REMOVE_INCOMING => session.remove_incoming(packet_id),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown session journal record",
+            )),
+        }
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn put_outgoing(&mut self, outgoing: &OutgoingPublish) -> io::Result<()> {
+        let record = outgoing_record(outgoing)?;
+        self.outgoing.insert(outgoing.packet_id);
+        self.append(&record)
+    }
+
+    fn remove_outgoing(&mut self, packet_id: u16) -> io::Result<()> {
+        self.outgoing.remove(&packet_id);
+        self.append(&packet_id_record(REMOVE_OUTGOING, packet_id))
+    }
+
+    fn put_incoming(&mut self, packet_id: u16) -> io::Result<()> {
+        self.incoming.insert(packet_id);
+        self.append(&packet_id_record(PUT_INCOMING, packet_id))
+    }
+
+    fn remove_incoming(&mut self, packet_id: u16) -> io::Result<()> {
+        self.incoming.remove(&packet_id);
+        self.append(&packet_id_record(REMOVE_INCOMING, packet_id))
+    }
+
+    fn load(&mut self) -> io::Result<StoredSession> {
+        self.compact()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.file.sync_data()?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = 0;
+        self.outgoing.clear();
+        self.incoming.clear();
+        Ok(())
+    }
+}
+
+fn outgoing_record(outgoing: &OutgoingPublish) -> io::Result<Vec<u8>> {
+    let state: u8 = match outgoing.state {
+        OutgoingState::AwaitingPubAck => 0,
+        OutgoingState::AwaitingPubRec => 1,
+        OutgoingState::AwaitingPubComp => 2,
+    };
+    let message = &outgoing.message;
+    let topic_length = u16::try_from(message.topic.len()).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "topics are limited to 65535 bytes",
+        )
+    })?;
+    let mut record: Vec<u8> = vec![PUT_OUTGOING];
+    record.extend_from_slice(&outgoing.packet_id.to_be_bytes());
+    record.push(state);
+    record.push(message.qos.into());
+    record.push(message.retain as u8);
+    record.extend_from_slice(&topic_length.to_be_bytes());
+    record.extend_from_slice(message.topic.as_bytes());
+    record.extend_from_slice(&(message.payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&message.payload);
+    Ok(record)
+}
+
+fn packet_id_record(record_type: u8, packet_id: u16) -> Vec<u8> {
+    let mut record: Vec<u8> = vec![record_type];
+    record.extend_from_slice(&packet_id.to_be_bytes());
+    record
+}
+
+fn read_bytes(reader: &mut &[u8], length: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; length];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u8(reader: &mut &[u8]) -> io::Result<u8> {
+    Ok(read_bytes(reader, 1)?[0])
+}
+
+fn read_u16(reader: &mut &[u8]) -> io::Result<u16> {
+    let bytes = read_bytes(reader, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(reader: &mut &[u8]) -> io::Result<u32> {
+    let bytes = read_bytes(reader, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod session_store_tests {
+    use std::{
+        fs,
+        io::{self, Write},
+        path::PathBuf,
+    };
+
+    use crate::{
+        connect_packet::QoS,
+        publish_packet::Message,
+        session_state::{OutgoingPublish, OutgoingState},
+    };
+
+    use super::{
+        outgoing_record, FileSessionStore, MemorySessionStore, SessionStore, MIN_COMPACTION_RECORDS,
+    };
+
+    fn outgoing(packet_id: u16, state: OutgoingState) -> OutgoingPublish {
+        OutgoingPublish {
+            packet_id,
+            message: Message {
+                topic: format!("billing/{}", packet_id),
+                payload: vec![0, 1, 2, 255],
+                qos: QoS::ExactlyOnce,
+                retain: true,
+            },
+            state,
+        }
+    }
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mqutekitty-{}-{}.session",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn exercise(store: &mut dyn SessionStore) {
+        store
+            .put_outgoing(&outgoing(1, OutgoingState::AwaitingPubRec))
+            .unwrap();
+        store
+            .put_outgoing(&outgoing(2, OutgoingState::AwaitingPubRec))
+            .unwrap();
+        store
+            .put_outgoing(&outgoing(3, OutgoingState::AwaitingPubRec))
+            .unwrap();
+        store
+            .put_outgoing(&outgoing(1, OutgoingState::AwaitingPubComp))
+            .unwrap();
+        store.remove_outgoing(2).unwrap();
+        store.put_incoming(7).unwrap();
+        store.put_incoming(8).unwrap();
+        store.remove_incoming(7).unwrap();
+    }
+
+    #[test]
+    fn memory_store_test() {
+        let mut store = MemorySessionStore::new();
+        exercise(&mut store);
+
+        let stored = store.load().unwrap();
+
+        assert_eq!(
+            stored.outgoing,
+            vec![
+                outgoing(1, OutgoingState::AwaitingPubComp),
+                outgoing(3, OutgoingState::AwaitingPubRec)
+            ]
+        );
+        assert_eq!(stored.incoming, vec![8]);
+
+        store.clear().unwrap();
+        assert_eq!(store.load().unwrap(), Default::default());
+    }
+
+    #[test]
+    fn file_store_test() {
+        let path = journal_path("file_store");
+        let mut store = FileSessionStore::open(&path).unwrap();
+        exercise(&mut store);
+        drop(store);
+
+        // a new process opens the same file
+        let mut store = FileSessionStore::open(&path).unwrap();
+        let stored = store.load().unwrap();
+        assert_eq!(
+            stored.outgoing,
+            vec![
+                outgoing(1, OutgoingState::AwaitingPubComp),
+                outgoing(3, OutgoingState::AwaitingPubRec)
+            ]
+        );
+        assert_eq!(stored.incoming, vec![8]);
+
+        // the journal was compacted and is still appendable
+        store.remove_outgoing(1).unwrap();
+        let stored = FileSessionStore::open(&path).unwrap().load().unwrap();
+        assert_eq!(
+            stored.outgoing,
+            vec![outgoing(3, OutgoingState::AwaitingPubRec)]
+        );
+
+        store.clear().unwrap();
+        let stored = FileSessionStore::open(&path).unwrap().load().unwrap();
+        assert_eq!(stored, Default::default());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn topic_too_long_test() {
+        let path = journal_path("topic_too_long");
+        let mut store = FileSessionStore::open(&path).unwrap();
+        let mut publish = outgoing(1, OutgoingState::AwaitingPubAck);
+        publish.message.topic = "a".repeat(u16::MAX as usize + 1);
+
+        let error = store.put_outgoing(&publish).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.load().unwrap(), Default::default());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction_test() {
+        let path = journal_path("compaction");
+        let mut store = FileSessionStore::open(&path).unwrap();
+        store.put_incoming(9).unwrap();
+        let record_length = outgoing_record(&outgoing(1, OutgoingState::AwaitingPubAck))
+            .unwrap()
+            .len();
+
+        // a long running client, every message is acknowledged and removed again
+        for round in 0..3 * MIN_COMPACTION_RECORDS {
+            let packet_id = (round % 100) as u16 + 1;
+            store
+                .put_outgoing(&outgoing(packet_id, OutgoingState::AwaitingPubAck))
+                .unwrap();
+            store.remove_outgoing(packet_id).unwrap();
+        }
+        store
+            .put_outgoing(&outgoing(42, OutgoingState::AwaitingPubRec))
+            .unwrap();
+
+        let journal_length = fs::metadata(&path).unwrap().len() as usize;
+        assert!(journal_length < MIN_COMPACTION_RECORDS * record_length);
+        let stored = FileSessionStore::open(&path).unwrap().load().unwrap();
+        assert_eq!(
+            stored.outgoing,
+            vec![outgoing(42, OutgoingState::AwaitingPubRec)]
+        );
+        assert_eq!(stored.incoming, vec![9]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_write_test() {
+        let path = journal_path("torn_write");
+        let mut store = FileSessionStore::open(&path).unwrap();
+        store.put_incoming(5).unwrap();
+        drop(store);
+        // the process died halfway through the next record
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[1, 0, 6, 1])
+            .unwrap();
+
+        let stored = FileSessionStore::open(&path).unwrap().load().unwrap();
+
+        assert_eq!(stored.incoming, vec![5]);
+        assert!(stored.outgoing.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
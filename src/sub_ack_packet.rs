@@ -0,0 +1,90 @@
+use std::io;
+
+use crate::{
+    connect_packet::QoS,
+    control_packets::{decode_packet_id, malformed, FixedHeader},
+};
+
+// 3.9. SUBACK - Subscribe acknowledgement
+// A SUBACK Packet is sent by the Server to the Client to confirm receipt and processing of a SUBSCRIBE Packet.
+// It contains the Packet Identifier of the SUBSCRIBE it acknowledges,
+// followed by a list of return codes, one for each Topic Filter in the SUBSCRIBE, in the same order [MQTT-3.9.3-1].
+//
+// 3.9.3. Payload
+//  -------------------------------------------
+// | Return code | Meaning                     |
+//  -------------------------------------------
+// | 0x00        | Success - Maximum QoS 0     |
+// | 0x01        | Success - Maximum QoS 1     |
+// | 0x02        | Success - Maximum QoS 2     |
+// | 0x80        | Failure                     |
+//  -------------------------------------------
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum SubscribeReturnCode {
+    Success(QoS),
+    Failure,
+}
+
+impl From<u8> for SubscribeReturnCode {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => SubscribeReturnCode::Success(QoS::AtMostOnce),
+            0x01 => SubscribeReturnCode::Success(QoS::AtLeastOnce),
+            0x02 => SubscribeReturnCode::Success(QoS::ExactlyOnce),
+            _ => SubscribeReturnCode::Failure,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SubAckPacket {
+    pub fixed_header: FixedHeader,
+    pub packet_id: u16,
+    pub return_codes: Vec<SubscribeReturnCode>,
+}
+
+impl TryFrom<&[u8]> for SubAckPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, variable_header) = FixedHeader::split(bytes)?;
+        let packet_id = decode_packet_id(variable_header).ok_or_else(|| malformed("SUBACK"))?;
+        Ok(SubAckPacket {
+            fixed_header,
+            packet_id,
+            return_codes: variable_header[2..]
+                .iter()
+                .map(|return_code| SubscribeReturnCode::from(*return_code))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod sub_ack_packet_tests {
+    use crate::{connect_packet::QoS, control_packets::ControlPacketType};
+
+    use super::{SubAckPacket, SubscribeReturnCode};
+
+    #[test]
+    fn decode_test() {
+        let sub_ack_packet_bytes: [u8; 7] = [0b1001_0000, 5, 0x00, 0x0a, 0x00, 0x02, 0x80];
+
+        let sub_ack_packet = SubAckPacket::try_from(&sub_ack_packet_bytes[..]).unwrap();
+
+        assert_eq!(
+            sub_ack_packet.fixed_header.packet_type,
+            ControlPacketType::SubAck
+        );
+        assert_eq!(sub_ack_packet.packet_id, 10);
+        assert_eq!(
+            sub_ack_packet.return_codes,
+            vec![
+                SubscribeReturnCode::Success(QoS::AtMostOnce),
+                SubscribeReturnCode::Success(QoS::ExactlyOnce),
+                SubscribeReturnCode::Failure,
+            ]
+        );
+    }
+}
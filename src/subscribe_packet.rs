@@ -1,4 +1,4 @@
-use std::{error::Error, vec};
+use std::{error::Error, fmt, vec};
 
 use crate::{
     connect_packet::QoS,
@@ -10,12 +10,31 @@ pub struct TopicFilter<'a> {
     pub requested_qos: QoS,
 }
 
+#[derive(Debug, Clone)]
+pub struct ErrorBuildingSubscribePacket;
+impl fmt::Display for ErrorBuildingSubscribePacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Error building subscribe packet: a non-zero packet id is required"
+        )
+    }
+}
+
+impl Error for ErrorBuildingSubscribePacket {}
+
 pub struct Builder<'a> {
     fixed_header: FixedHeader,
-    packet_id: u16, // must be a non-zero value
+    packet_id: Option<u16>, // must be a non-zero value
     topic_filters: Vec<TopicFilter<'a>>,
 }
 
+impl<'a> Default for Builder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> Builder<'a> {
     pub fn new() -> Self {
         Builder {
@@ -24,13 +43,13 @@ impl<'a> Builder<'a> {
                 packet_flags: ControlPacketFlags::SUBSCRIBE_FLAGS,
                 remaining_length: 0,
             },
-            packet_id: 1,
+            packet_id: None,
             topic_filters: vec![],
         }
     }
 
     pub fn packet_id(&mut self, packet_id: u16) -> &mut Self {
-        self.packet_id = packet_id;
+        self.packet_id = Some(packet_id);
         self
     }
 
@@ -48,11 +67,15 @@ impl<'a> Builder<'a> {
         remaining_length
     }
 
-    pub fn build(&mut self) -> Result<SubscribePacket, Box<dyn Error>> {
+    pub fn build(&mut self) -> Result<SubscribePacket<'_>, Box<dyn Error>> {
+        let packet_id = match self.packet_id {
+            Some(packet_id) if packet_id != 0 => packet_id,
+            _ => return Err(Box::new(ErrorBuildingSubscribePacket)),
+        };
         self.fixed_header.remaining_length = self.calc_remaining_length();
         Ok(SubscribePacket {
             fixed_header: self.fixed_header,
-            packet_id: self.packet_id,
+            packet_id,
             topic_filters: &self.topic_filters,
         })
     }
@@ -66,12 +89,12 @@ pub struct SubscribePacket<'a> {
 impl<'a> Encodable for SubscribePacket<'a> {
     fn encode(&self) -> Vec<u8> {
         let mut vec: Vec<u8> = Vec::new();
-        vec.extend_from_slice(&mut self.fixed_header.encode());
+        vec.extend_from_slice(&self.fixed_header.encode());
         vec.extend_from_slice(&self.packet_id.to_be_bytes());
 
         for topic_filter in self.topic_filters.iter() {
             vec.extend_from_slice(&(topic_filter.topic_name.len() as u16).to_be_bytes());
-            vec.extend_from_slice(&topic_filter.topic_name.as_bytes());
+            vec.extend_from_slice(topic_filter.topic_name.as_bytes());
             vec.push(topic_filter.requested_qos.into());
         }
         vec
@@ -90,7 +113,7 @@ mod subscribe_packet_tests {
             requested_qos: crate::connect_packet::QoS::AtMostOnce,
         };
         let mut builder = super::Builder::new();
-        let subscribe_packet = builder.topic_filter(topic_filter).build();
+        let subscribe_packet = builder.packet_id(1).topic_filter(topic_filter).build();
         let subscribe_packet_bytes = subscribe_packet.unwrap().encode();
 
         println!("{:?}", subscribe_packet_bytes);
@@ -98,7 +121,7 @@ mod subscribe_packet_tests {
         assert_eq!(subscribe_packet_bytes[0], 0b1000_0010); // control packet type + reserved
         assert_eq!(subscribe_packet_bytes[1], 8); // remaining length
         assert_eq!(subscribe_packet_bytes[2], 0); // packet id MSB
-        assert_eq!(subscribe_packet_bytes[3], 0); // packet id LSB
+        assert_eq!(subscribe_packet_bytes[3], 1); // packet id LSB
         assert_eq!(subscribe_packet_bytes[4], 0); // topic filter length MSB
         assert_eq!(subscribe_packet_bytes[5], 3); // topic filter length LSB
         assert_eq!(subscribe_packet_bytes[6], 0x61); // 'a'
@@ -106,4 +129,17 @@ mod subscribe_packet_tests {
         assert_eq!(subscribe_packet_bytes[8], 0x62); // 'b'
         assert_eq!(subscribe_packet_bytes[9], 0); // topic filter requested QoS
     }
+
+    #[test]
+    fn missing_packet_id_test() {
+        let topic_filter = super::TopicFilter {
+            topic_name: "a/b",
+            requested_qos: crate::connect_packet::QoS::AtMostOnce,
+        };
+        assert!(super::Builder::new()
+            .topic_filter(topic_filter)
+            .build()
+            .is_err());
+        assert!(super::Builder::new().packet_id(0).build().is_err());
+    }
 }
@@ -0,0 +1,502 @@
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{
+        pem::{self, PemObject},
+        CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName,
+    },
+    ClientConfig, RootCertStore,
+};
+use tokio_rustls::TlsConnector;
+use tracing::{info, warn};
+
+// TLS settings for brokers that require it, usually on port 8883.
+// Without a CA the Mozilla roots of webpki-roots are trusted. The server name the certificate
+// is checked against is the host of the address, unless it's overridden.
+// A client certificate is presented to brokers that authenticate clients by certificate. One
+// loaded from files is loaded again before a connect when the files changed since, so a renewed
+// certificate is picked up by the next reconnect.
+
+#[derive(Debug)]
+pub enum TlsError {
+    // a CA or client certificate file can't be read or isn't valid PEM
+    InvalidPem(rustls::pki_types::pem::Error),
+    // a CA file or PEM holds no certificate
+    NoCertificates,
+    // a CA certificate can't be used as a trust anchor
+    InvalidCertificate(rustls::Error),
+    // the client key file or PEM holds no private key
+    NoPrivateKey,
+    // the client certificate chain and key can't be used together
+    InvalidClientIdentity(rustls::Error),
+    InvalidServerName(String),
+    // protocols are limited to 255 bytes and can't be empty
+    InvalidAlpnProtocol,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::InvalidPem(error) => write!(f, "Failed to read the PEM: {}", error),
+            TlsError::NoCertificates => write!(f, "The PEM holds no certificates"),
+            TlsError::InvalidCertificate(error) => {
+                write!(f, "Invalid CA certificate: {}", error)
+            }
+            TlsError::NoPrivateKey => write!(f, "No private key found"),
+            TlsError::InvalidClientIdentity(error) => {
+                write!(f, "Invalid client certificate or key: {}", error)
+            }
+            TlsError::InvalidServerName(name) => write!(f, "Invalid server name {}", name),
+            TlsError::InvalidAlpnProtocol => {
+                write!(f, "ALPN protocols must be 1 to 255 bytes long")
+            }
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::InvalidPem(error) => Some(error),
+            TlsError::InvalidCertificate(error) => Some(error),
+            TlsError::InvalidClientIdentity(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    roots: Arc<RootCertStore>,
+    server_name: Option<ServerName<'static>>,
+    alpn_protocols: Vec<Vec<u8>>,
+    // shared by the clones, so a reload is seen by every client using the options
+    client_identity: Option<Arc<ClientIdentity>>,
+}
+
+impl TlsOptions {
+    // TLS with the webpki roots and nothing else configured
+    pub fn new() -> TlsOptions {
+        Builder::new().build().unwrap()
+    }
+
+    pub fn server_name(&self) -> Option<&ServerName<'static>> {
+        self.server_name.as_ref()
+    }
+
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.alpn_protocols
+    }
+
+    // The name the server's certificate has to be valid for, the host part of the address
+    // unless it's overridden
+    pub(crate) fn server_name_for(&self, address: &str) -> io::Result<ServerName<'static>> {
+        if let Some(server_name) = &self.server_name {
+            return Ok(server_name.clone());
+        }
+        ServerName::try_from(host(address).to_string())
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))
+    }
+
+    pub(crate) fn connector(&self) -> io::Result<TlsConnector> {
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default protocol versions")
+            .with_root_certificates(Arc::clone(&self.roots));
+        let mut config = match &self.client_identity {
+            Some(client_identity) => {
+                let (certificates, key) = client_identity.current();
+                builder
+                    .with_client_auth_cert(certificates, key)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = self.alpn_protocols.clone();
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// "host:port", "[v6 address]:port" or just the host
+fn host(address: &str) -> &str {
+    if let Some(bracketed) = address.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match address.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => address,
+    }
+}
+
+#[derive(Debug)]
+struct ClientIdentity {
+    // None for an identity given as PEM, which never changes
+    files: Option<IdentityFiles>,
+    loaded: Mutex<LoadedIdentity>,
+}
+
+#[derive(Debug)]
+struct IdentityFiles {
+    certificates: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Debug)]
+struct LoadedIdentity {
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    // when the files were modified, as of loading them
+    modified: Option<(SystemTime, SystemTime)>,
+}
+
+impl ClientIdentity {
+    fn from_files(certificates: &Path, key: &Path) -> Result<ClientIdentity, TlsError> {
+        let files = IdentityFiles {
+            certificates: certificates.to_path_buf(),
+            key: key.to_path_buf(),
+        };
+        let loaded = files.load()?;
+        Ok(ClientIdentity {
+            files: Some(files),
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    fn from_pem(certificates: &[u8], key: &[u8]) -> Result<ClientIdentity, TlsError> {
+        Ok(ClientIdentity {
+            files: None,
+            loaded: Mutex::new(LoadedIdentity {
+                certificates: parse_certificates(certificates)?,
+                key: parse_key(key)?,
+                modified: None,
+            }),
+        })
+    }
+
+    // Loads the files again if they were modified since. A file that can't be loaded, e.g.
+    // because it's only half written, keeps the previous identity in use.
+    fn current(&self) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+        let mut loaded = self.loaded.lock().unwrap();
+        if let Some(files) = &self.files {
+            let modified = files.modified().ok();
+            if modified.is_none() || modified != loaded.modified {
+                match files.load() {
+                    Ok(reloaded) => {
+                        info!("Loaded the client certificate {:?}", files.certificates);
+                        *loaded = reloaded;
+                    }
+                    Err(error) => warn!(
+                        "Failed to reload the client certificate {:?}, keeping the previous one: {}",
+                        files.certificates, error
+                    ),
+                }
+            }
+        }
+        (loaded.certificates.clone(), loaded.key.clone_key())
+    }
+}
+
+impl IdentityFiles {
+    fn modified(&self) -> io::Result<(SystemTime, SystemTime)> {
+        Ok((
+            fs::metadata(&self.certificates)?.modified()?,
+            fs::metadata(&self.key)?.modified()?,
+        ))
+    }
+
+    fn load(&self) -> Result<LoadedIdentity, TlsError> {
+        // taken before reading, so a change while reading is picked up by the next load
+        let modified = self.modified().ok();
+        let read = |path: &Path| {
+            fs::read(path).map_err(|error| TlsError::InvalidPem(pem::Error::Io(error)))
+        };
+        Ok(LoadedIdentity {
+            certificates: parse_certificates(&read(&self.certificates)?)?,
+            key: parse_key(&read(&self.key)?)?,
+            modified,
+        })
+    }
+}
+
+// PEM, or a single DER certificate
+fn parse_certificates(bytes: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    if !is_pem(bytes) {
+        return Ok(vec![CertificateDer::from(bytes.to_vec())]);
+    }
+    let certificates = CertificateDer::pem_slice_iter(bytes)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(TlsError::InvalidPem)?;
+    match certificates.is_empty() {
+        true => Err(TlsError::NoCertificates),
+        false => Ok(certificates),
+    }
+}
+
+// A PEM PKCS#8, PKCS#1 or SEC1 key, or a DER PKCS#8 key
+fn parse_key(bytes: &[u8]) -> Result<PrivateKeyDer<'static>, TlsError> {
+    if !is_pem(bytes) {
+        return Ok(PrivatePkcs8KeyDer::from(bytes.to_vec()).into());
+    }
+    PrivateKeyDer::from_pem_slice(bytes).map_err(|error| match error {
+        pem::Error::NoItemsFound => TlsError::NoPrivateKey,
+        error => TlsError::InvalidPem(error),
+    })
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.trim_ascii_start().starts_with(b"-----BEGIN")
+}
+
+enum CaSource {
+    File(PathBuf),
+    Pem(Vec<u8>),
+}
+
+enum IdentitySource {
+    Files { certificates: PathBuf, key: PathBuf },
+    Pem { certificates: Vec<u8>, key: Vec<u8> },
+}
+
+#[derive(Default)]
+pub struct Builder {
+    ca: Vec<CaSource>,
+    server_name: Option<String>,
+    alpn_protocols: Vec<Vec<u8>>,
+    client_identity: Option<IdentitySource>,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    // Trusts the certificates of the PEM file instead of the webpki roots, can be called repeatedly
+    pub fn ca_file(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.ca.push(CaSource::File(path.into()));
+        self
+    }
+
+    pub fn ca_pem(&mut self, pem: &[u8]) -> &mut Self {
+        self.ca.push(CaSource::Pem(pem.to_vec()));
+        self
+    }
+
+    // Checks the certificate against this name instead of the host of the address
+    pub fn server_name(&mut self, server_name: &str) -> &mut Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    // Offered in the order they are added, e.g. "mqtt" for brokers sharing port 443
+    pub fn alpn_protocol(&mut self, protocol: &[u8]) -> &mut Self {
+        self.alpn_protocols.push(protocol.to_vec());
+        self
+    }
+
+    // The certificate chain and key presented to brokers that ask for a client certificate,
+    // both PEM or DER. Loaded again before a connect when either file changed.
+    pub fn client_auth_files(
+        &mut self,
+        certificates: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> &mut Self {
+        self.client_identity = Some(IdentitySource::Files {
+            certificates: certificates.into(),
+            key: key.into(),
+        });
+        self
+    }
+
+    pub fn client_auth_pem(&mut self, certificates: &[u8], key: &[u8]) -> &mut Self {
+        self.client_identity = Some(IdentitySource::Pem {
+            certificates: certificates.to_vec(),
+            key: key.to_vec(),
+        });
+        self
+    }
+
+    pub fn build(&mut self) -> Result<TlsOptions, TlsError> {
+        let roots = match self.ca.is_empty() {
+            true => RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            },
+            false => self.load_ca()?,
+        };
+        let server_name = self
+            .server_name
+            .as_ref()
+            .map(|name| {
+                ServerName::try_from(name.clone())
+                    .map_err(|_| TlsError::InvalidServerName(name.clone()))
+            })
+            .transpose()?;
+        if self
+            .alpn_protocols
+            .iter()
+            .any(|protocol| protocol.is_empty() || protocol.len() > u8::MAX as usize)
+        {
+            return Err(TlsError::InvalidAlpnProtocol);
+        }
+        let client_identity = match &self.client_identity {
+            Some(IdentitySource::Files { certificates, key }) => {
+                Some(ClientIdentity::from_files(certificates, key)?)
+            }
+            Some(IdentitySource::Pem { certificates, key }) => {
+                Some(ClientIdentity::from_pem(certificates, key)?)
+            }
+            None => None,
+        };
+        if let Some(client_identity) = &client_identity {
+            // a key that doesn't belong to the certificate is reported now rather than on connect
+            let (certificates, key) = client_identity.current();
+            ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("the ring provider supports the default protocol versions")
+                .with_root_certificates(RootCertStore::empty())
+                .with_client_auth_cert(certificates, key)
+                .map_err(TlsError::InvalidClientIdentity)?;
+        }
+        Ok(TlsOptions {
+            roots: Arc::new(roots),
+            server_name,
+            alpn_protocols: self.alpn_protocols.clone(),
+            client_identity: client_identity.map(Arc::new),
+        })
+    }
+
+    fn load_ca(&self) -> Result<RootCertStore, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for source in &self.ca {
+            let certificates = match source {
+                CaSource::File(path) => CertificateDer::pem_file_iter(path)
+                    .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>()),
+                CaSource::Pem(pem) => CertificateDer::pem_slice_iter(pem).collect(),
+            }
+            .map_err(TlsError::InvalidPem)?;
+            if certificates.is_empty() {
+                return Err(TlsError::NoCertificates);
+            }
+            for certificate in certificates {
+                roots
+                    .add(certificate)
+                    .map_err(TlsError::InvalidCertificate)?;
+            }
+        }
+        Ok(roots)
+    }
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use rustls::pki_types::ServerName;
+
+    use super::{host, Builder, TlsError};
+
+    #[test]
+    fn host_test() {
+        assert_eq!(host("broker.local:8883"), "broker.local");
+        assert_eq!(host("[::1]:8883"), "::1");
+        assert_eq!(host("broker.local"), "broker.local");
+    }
+
+    #[test]
+    fn builder_test() {
+        let options = Builder::new()
+            .server_name("broker.local")
+            .alpn_protocol(b"mqtt")
+            .build()
+            .unwrap();
+        assert_eq!(
+            options.server_name_for("127.0.0.1:8883").unwrap(),
+            ServerName::try_from("broker.local").unwrap()
+        );
+        assert_eq!(options.alpn_protocols(), &[b"mqtt".to_vec()]);
+
+        let options = Builder::new().build().unwrap();
+        assert_eq!(
+            options.server_name_for("127.0.0.1:8883").unwrap(),
+            ServerName::try_from("127.0.0.1").unwrap()
+        );
+    }
+
+    #[test]
+    fn client_identity_test() {
+        let identity = rcgen::generate_simple_self_signed(vec!["device".to_string()]).unwrap();
+        let other_key = rcgen::KeyPair::generate().unwrap();
+        let certificate = identity.cert.pem();
+        let key = identity.signing_key.serialize_pem();
+
+        assert!(matches!(
+            Builder::new()
+                .client_auth_pem(key.as_bytes(), key.as_bytes())
+                .build(),
+            Err(TlsError::NoCertificates)
+        ));
+        assert!(matches!(
+            Builder::new()
+                .client_auth_pem(certificate.as_bytes(), certificate.as_bytes())
+                .build(),
+            Err(TlsError::NoPrivateKey)
+        ));
+        assert!(matches!(
+            Builder::new()
+                .client_auth_files("/does/not/exist.pem", "/does/not/exist.key")
+                .build(),
+            Err(TlsError::InvalidPem(_))
+        ));
+        assert!(Builder::new()
+            .client_auth_pem(certificate.as_bytes(), key.as_bytes())
+            .build()
+            .is_ok());
+        // PKCS#8 DER works as well
+        assert!(Builder::new()
+            .client_auth_pem(identity.cert.der(), &identity.signing_key.serialize_der())
+            .build()
+            .is_ok());
+        assert!(matches!(
+            Builder::new()
+                .client_auth_pem(certificate.as_bytes(), b"")
+                .build(),
+            Err(TlsError::InvalidClientIdentity(_))
+        ));
+        assert!(matches!(
+            Builder::new()
+                .client_auth_pem(certificate.as_bytes(), other_key.serialize_pem().as_bytes())
+                .build(),
+            Err(TlsError::InvalidClientIdentity(_))
+        ));
+    }
+
+    #[test]
+    fn invalid_options_test() {
+        assert!(matches!(
+            Builder::new().ca_pem(b"no certificates").build(),
+            Err(TlsError::NoCertificates)
+        ));
+        assert!(matches!(
+            Builder::new().ca_file("/does/not/exist.pem").build(),
+            Err(TlsError::InvalidPem(_))
+        ));
+        assert!(matches!(
+            Builder::new().server_name("not a name").build(),
+            Err(TlsError::InvalidServerName(_))
+        ));
+        assert!(matches!(
+            Builder::new().alpn_protocol(b"").build(),
+            Err(TlsError::InvalidAlpnProtocol)
+        ));
+    }
+}
@@ -0,0 +1,554 @@
+use std::{io, net::SocketAddr};
+
+use futures::future::BoxFuture;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+use crate::{
+    happy_eyeballs,
+    mqtt_options::MqttOptions,
+    tls::TlsOptions,
+    websocket::{WebSocket, WebSocketOptions},
+};
+
+// Addresses starting with this are paths of Unix domain sockets, e.g. "unix:/run/mqtt.sock"
+pub const UNIX_PREFIX: &str = "unix:";
+
+// Whatever byte stream a connection runs over. TCP, TLS, WebSockets, Unix domain sockets and
+// in-memory pipes all are one.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+pub type BoxedTransport = Box<dyn Transport>;
+
+// A transport a connector opened
+pub struct Established {
+    pub transport: BoxedTransport,
+    // where the TCP connection went, the proxy's address if there is one.
+    // None for transports that aren't TCP.
+    pub remote_address: Option<SocketAddr>,
+}
+
+impl Established {
+    pub fn new(transport: BoxedTransport, remote_address: Option<SocketAddr>) -> Self {
+        Established {
+            transport,
+            remote_address,
+        }
+    }
+}
+
+// Opens the transport the client connects over. The connection task calls it for every
+// connect, the handshake with the Server happens on the returned transport.
+pub trait Connector: Send + Sync {
+    fn connect<'a>(
+        &'a self,
+        address: &'a str,
+        options: &'a MqttOptions,
+    ) -> BoxFuture<'a, io::Result<Established>>;
+}
+
+// What the client connects over unless told otherwise: TCP, through a proxy if there is one,
+// TLS and WebSockets as configured in the options, or a Unix domain socket for unix: addresses.
+// Host names with several addresses are connected to with Happy Eyeballs.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetworkConnector;
+
+impl Connector for NetworkConnector {
+    fn connect<'a>(
+        &'a self,
+        address: &'a str,
+        options: &'a MqttOptions,
+    ) -> BoxFuture<'a, io::Result<Established>> {
+        Box::pin(connect(address, options))
+    }
+}
+
+pub(crate) async fn connect(address: &str, options: &MqttOptions) -> io::Result<Established> {
+    let (tls, websocket) = (options.tls(), options.websocket());
+    if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+        if options.proxy().is_some() || tls.is_some() || websocket.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "proxies, TLS and WebSockets aren't supported over Unix domain sockets",
+            ));
+        }
+        return Ok(Established::new(connect_unix(path).await?, None));
+    }
+
+    let (stream, remote_address) = connect_tcp(address, options).await?;
+    let transport = layer(Box::new(stream), address, tls, websocket).await?;
+    Ok(Established::new(transport, Some(remote_address)))
+}
+
+// A TCP connection to the address, tunneled through the proxy if the options have one.
+// Returns the address the connection went to.
+pub(crate) async fn connect_tcp(
+    address: &str,
+    options: &MqttOptions,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let attempt_delay = options.connection_attempt_delay();
+    let (stream, remote_address) = match options.proxy() {
+        Some(proxy) => proxy.connect(address, attempt_delay).await?,
+        None => happy_eyeballs::connect(address, attempt_delay).await?,
+    };
+    stream.set_nodelay(true)?;
+    Ok((stream, remote_address))
+}
+
+// Runs TLS and then the WebSocket, each if configured, on top of the connection
+pub(crate) async fn layer(
+    stream: BoxedTransport,
+    address: &str,
+    tls: Option<&TlsOptions>,
+    websocket: Option<&WebSocketOptions>,
+) -> io::Result<BoxedTransport> {
+    let transport: BoxedTransport = match tls {
+        Some(tls) => {
+            let server_name = tls.server_name_for(address)?;
+            Box::new(tls.connector()?.connect(server_name, stream).await?)
+        }
+        None => stream,
+    };
+    match websocket {
+        Some(websocket) => Ok(Box::new(
+            WebSocket::connect(transport, address, tls.is_some(), websocket).await?,
+        )),
+        None => Ok(transport),
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> io::Result<BoxedTransport> {
+    Ok(Box::new(UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> io::Result<BoxedTransport> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are only supported on Unix",
+    ))
+}
+
+// In-memory transports for testing the client without any sockets. Every connect creates a
+// tokio::io::duplex pair, the client gets one end and the listener the other. The address
+// is ignored.
+pub fn duplex(max_buf_size: usize) -> (DuplexConnector, DuplexListener) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (
+        DuplexConnector {
+            max_buf_size,
+            sender,
+        },
+        DuplexListener { receiver },
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct DuplexConnector {
+    max_buf_size: usize,
+    sender: mpsc::UnboundedSender<DuplexStream>,
+}
+
+impl Connector for DuplexConnector {
+    fn connect<'a>(
+        &'a self,
+        _address: &'a str,
+        _options: &'a MqttOptions,
+    ) -> BoxFuture<'a, io::Result<Established>> {
+        Box::pin(async move {
+            let (client, server) = tokio::io::duplex(self.max_buf_size);
+            self.sender.send(server).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "the duplex listener is gone",
+                )
+            })?;
+            Ok(Established::new(Box::new(client), None))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct DuplexListener {
+    receiver: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl DuplexListener {
+    // The Server's end of the next connection, None once every connector is gone
+    pub async fn accept(&mut self) -> Option<DuplexStream> {
+        self.receiver.recv().await
+    }
+}
+
+#[cfg(test)]
+mod transport_tests {
+    use std::{
+        fs, io,
+        path::PathBuf,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore, ServerConfig,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::{
+        mqtt_options::{self, MqttOptions},
+        proxy::{proxy_tests, Proxy},
+        tls,
+    };
+
+    use super::{connect, duplex, BoxedTransport, Connector};
+
+    // A CA and a certificate for localhost it signed
+    struct TestPki {
+        ca: CertifiedIssuer<'static, KeyPair>,
+        server_chain: Vec<CertificateDer<'static>>,
+        server_key: PrivateKeyDer<'static>,
+    }
+
+    impl TestPki {
+        fn new() -> TestPki {
+            let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+            let server_key = KeyPair::generate().unwrap();
+            let server_certificate = CertificateParams::new(vec!["localhost".to_string()])
+                .unwrap()
+                .signed_by(&server_key, &ca)
+                .unwrap();
+            TestPki {
+                server_chain: vec![server_certificate.der().clone()],
+                server_key: PrivatePkcs8KeyDer::from(server_key.serialize_der()).into(),
+                ca,
+            }
+        }
+
+        fn ca_pem(&self) -> String {
+            self.ca.pem()
+        }
+
+        // A client certificate the CA signed and its PKCS#8 key, both PEM
+        fn client_identity(&self, name: &str) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &self.ca)
+                .unwrap();
+            (certificate.pem(), key.serialize_pem())
+        }
+    }
+
+    // What the server learned about the client during the handshake
+    struct Accepted {
+        client_chain: Vec<CertificateDer<'static>>,
+        alpn_protocol: Option<Vec<u8>>,
+    }
+
+    // Accepts one TLS connection and echoes what it reads.
+    // With client_auth set the client must present a certificate the test CA signed.
+    // Resolves with what the handshake negotiated, or None if it failed.
+    async fn tls_echo_server(
+        pki: &TestPki,
+        client_auth: bool,
+    ) -> (String, JoinHandle<Option<Accepted>>) {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_auth {
+            true => {
+                let mut roots = RootCertStore::empty();
+                roots.add(pki.ca.der().clone()).unwrap();
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            false => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(pki.server_chain.clone(), pki.server_key.clone_key())
+            .unwrap();
+        config.alpn_protocols = vec![b"mqtt".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.ok()?;
+            let connection = stream.get_ref().1;
+            let accepted = Accepted {
+                client_chain: connection
+                    .peer_certificates()
+                    .map(|chain| chain.to_vec())
+                    .unwrap_or_default(),
+                alpn_protocol: connection.alpn_protocol().map(|protocol| protocol.to_vec()),
+            };
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+            stream.flush().await.unwrap();
+            Some(accepted)
+        });
+        (address, server)
+    }
+
+    // Connects with the proxy and the TLS options
+    async fn open(
+        address: &str,
+        proxy: Option<&Proxy>,
+        tls: Option<&tls::TlsOptions>,
+    ) -> io::Result<BoxedTransport> {
+        let mut builder = mqtt_options::Builder::new();
+        builder.client_id("mqutekitty");
+        if let Some(proxy) = proxy {
+            builder.proxy(proxy.clone());
+        }
+        if let Some(tls) = tls {
+            builder.tls(tls.clone());
+        }
+        let options = builder.build().unwrap();
+        Ok(connect(address, &options).await?.transport)
+    }
+
+    async fn echo(transport: &mut BoxedTransport) -> io::Result<()> {
+        transport.write_all(b"ping").await?;
+        transport.flush().await?;
+        let mut echo = [0u8; 4];
+        transport.read_exact(&mut echo).await?;
+        assert_eq!(&echo, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn duplex_test() {
+        let (connector, mut listener) = duplex(64);
+        let options = MqttOptions::new("mqutekitty").unwrap();
+        let established = connector.connect("anywhere", &options).await.unwrap();
+        assert!(established.remote_address.is_none());
+        let mut transport = established.transport;
+        let server = tokio::spawn(async move {
+            let mut stream = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+            listener
+        });
+        echo(&mut transport).await.unwrap();
+        let mut listener = server.await.unwrap();
+        // the listener ends once every connector is gone
+        drop(connector);
+        assert!(listener.accept().await.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_test() {
+        let path = temp_path("unix-socket");
+        let _ = fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+        });
+
+        let address = format!("unix:{}", path.display());
+        let mut transport = open(&address, None, None).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        server.await.unwrap();
+
+        let tls = tls::TlsOptions::new();
+        assert_eq!(
+            open(&address, None, Some(&tls)).await.err().unwrap().kind(),
+            io::ErrorKind::Unsupported
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mqutekitty-{}-{}.pem", name, std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn tls_test() {
+        let pki = TestPki::new();
+        let (address, server) = tls_echo_server(&pki, false).await;
+        let tls = tls::Builder::new()
+            .ca_pem(pki.ca_pem().as_bytes())
+            .server_name("localhost")
+            .alpn_protocol(b"mqtt")
+            .build()
+            .unwrap();
+
+        let mut transport = open(&address, None, Some(&tls)).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        let accepted = server.await.unwrap().unwrap();
+        assert!(accepted.client_chain.is_empty());
+        assert_eq!(accepted.alpn_protocol, Some(b"mqtt".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn tls_through_proxy_test() {
+        let pki = TestPki::new();
+        let tls = tls::Builder::new()
+            .ca_pem(pki.ca_pem().as_bytes())
+            .server_name("localhost")
+            .alpn_protocol(b"mqtt")
+            .build()
+            .unwrap();
+
+        let (address, server) = tls_echo_server(&pki, false).await;
+        let (proxy_address, socks5) = proxy_tests::socks5_proxy().await;
+        let proxy = Proxy::Socks5 {
+            address: proxy_address,
+            credentials: proxy_tests::credentials("kitty", "meow"),
+        };
+        let mut transport = open(&address, Some(&proxy), Some(&tls)).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        assert!(server.await.unwrap().is_some());
+        drop(transport);
+        assert_eq!(socks5.await.unwrap().unwrap(), address);
+
+        let (address, server) = tls_echo_server(&pki, false).await;
+        let (proxy_address, http) = proxy_tests::http_proxy().await;
+        let proxy = Proxy::HttpConnect {
+            address: proxy_address,
+            credentials: proxy_tests::credentials("kitty", "meow"),
+        };
+        let mut transport = open(&address, Some(&proxy), Some(&tls)).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        assert!(server.await.unwrap().is_some());
+        drop(transport);
+        assert!(http.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn untrusted_certificate_test() {
+        let pki = TestPki::new();
+        let (address, server) = tls_echo_server(&pki, false).await;
+        // the webpki roots don't include the test CA
+        let tls = tls::Builder::new()
+            .server_name("localhost")
+            .build()
+            .unwrap();
+
+        let error = open(&address, None, Some(&tls)).await.err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(server.await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn wrong_server_name_test() {
+        let pki = TestPki::new();
+        let (address, server) = tls_echo_server(&pki, false).await;
+        let tls = tls::Builder::new()
+            .ca_pem(pki.ca_pem().as_bytes())
+            .server_name("broker.example")
+            .build()
+            .unwrap();
+
+        assert!(open(&address, None, Some(&tls)).await.is_err());
+        assert!(server.await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_test() {
+        let pki = TestPki::new();
+        let (certificate, key) = pki.client_identity("device-1");
+        let tls = tls::Builder::new()
+            .ca_pem(pki.ca_pem().as_bytes())
+            .server_name("localhost")
+            .client_auth_pem(certificate.as_bytes(), key.as_bytes())
+            .build()
+            .unwrap();
+
+        let (address, server) = tls_echo_server(&pki, true).await;
+        let mut transport = open(&address, None, Some(&tls)).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        let accepted = server.await.unwrap().unwrap();
+        assert_eq!(accepted.client_chain.len(), 1);
+        assert_eq!(accepted.alpn_protocol, None);
+
+        // without a certificate the server ends the handshake, TLS 1.3 clients learn about it
+        // on their first read
+        let tls = tls::Builder::new()
+            .ca_pem(pki.ca_pem().as_bytes())
+            .server_name("localhost")
+            .build()
+            .unwrap();
+        let (address, server) = tls_echo_server(&pki, true).await;
+        let refused = match open(&address, None, Some(&tls)).await {
+            Ok(mut transport) => echo(&mut transport).await.is_err(),
+            Err(_) => true,
+        };
+        assert!(refused);
+        assert!(server.await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_reload_test() {
+        let pki = TestPki::new();
+        let certificate_path = temp_path("client-certificate");
+        let key_path = temp_path("client-key");
+        let (first_certificate, first_key) = pki.client_identity("device-1");
+        fs::write(&certificate_path, &first_certificate).unwrap();
+        fs::write(&key_path, &first_key).unwrap();
+        let tls = tls::Builder::new()
+            .ca_pem(pki.ca_pem().as_bytes())
+            .server_name("localhost")
+            .client_auth_files(&certificate_path, &key_path)
+            .build()
+            .unwrap();
+
+        let (address, server) = tls_echo_server(&pki, true).await;
+        let mut transport = open(&address, None, Some(&tls)).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        let first_chain = server.await.unwrap().unwrap().client_chain;
+
+        // a renewed certificate, the modification time is moved so the change can't go unnoticed
+        let (second_certificate, second_key) = pki.client_identity("device-1");
+        fs::write(&certificate_path, &second_certificate).unwrap();
+        fs::write(&key_path, &second_key).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        for path in [&certificate_path, &key_path] {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(later)
+                .unwrap();
+        }
+
+        let (address, server) = tls_echo_server(&pki, true).await;
+        let mut transport = open(&address, None, Some(&tls)).await.unwrap();
+        echo(&mut transport).await.unwrap();
+        let second_chain = server.await.unwrap().unwrap().client_chain;
+        assert_ne!(first_chain, second_chain);
+
+        fs::remove_file(&certificate_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+    }
+}
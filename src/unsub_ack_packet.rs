@@ -0,0 +1,41 @@
+use std::io;
+
+use crate::control_packets::{decode_packet_id, malformed, FixedHeader};
+
+// 3.11. UNSUBACK - Unsubscribe acknowledgement
+// The UNSUBACK Packet is sent by the Server to the Client to confirm receipt of an UNSUBSCRIBE Packet.
+// It only contains the Packet Identifier of the UNSUBSCRIBE Packet that is being acknowledged.
+#[derive(Debug)]
+pub struct UnsubAckPacket {
+    pub fixed_header: FixedHeader,
+    pub packet_id: u16,
+}
+
+impl TryFrom<&[u8]> for UnsubAckPacket {
+    type Error = io::Error;
+
+    fn try_from(bytes: &[u8]) -> io::Result<Self> {
+        let (fixed_header, variable_header) = FixedHeader::split(bytes)?;
+        Ok(UnsubAckPacket {
+            fixed_header,
+            packet_id: decode_packet_id(variable_header).ok_or_else(|| malformed("UNSUBACK"))?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod unsub_ack_packet_tests {
+    use crate::control_packets::ControlPacketType;
+
+    use super::UnsubAckPacket;
+
+    #[test]
+    fn decode_test() {
+        let unsub_ack_packet = UnsubAckPacket::try_from(&[0b1011_0000, 2, 0, 3][..]).unwrap();
+        assert_eq!(
+            unsub_ack_packet.fixed_header.packet_type,
+            ControlPacketType::UnsubAck
+        );
+        assert_eq!(unsub_ack_packet.packet_id, 3);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{error::Error, fmt};
+
+use crate::control_packets::{ControlPacketFlags, ControlPacketType, Encodable, FixedHeader};
+
+// 3.10. UNSUBSCRIBE - Unsubscribe from topics
+// An UNSUBSCRIBE Packet is sent by the Client to the Server, to unsubscribe from topics.
+// The variable header contains a Packet Identifier,
+// the payload contains the list of Topic Filters to unsubscribe from.
+// The payload of an UNSUBSCRIBE packet MUST contain at least one Topic Filter [MQTT-3.10.3-2].
+
+#[derive(Debug, Clone)]
+pub struct ErrorBuildingUnsubscribePacket;
+impl fmt::Display for ErrorBuildingUnsubscribePacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Error building unsubscribe packet: a non-zero packet id and at least one topic filter are required"
+        )
+    }
+}
+
+impl Error for ErrorBuildingUnsubscribePacket {}
+
+pub struct Builder<'a> {
+    fixed_header: FixedHeader,
+    packet_id: Option<u16>, // must be a non-zero value
+    topic_filters: Vec<&'a str>,
+}
+
+impl<'a> Default for Builder<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Builder<'a> {
+    pub fn new() -> Self {
+        Builder {
+            fixed_header: FixedHeader {
+                packet_type: ControlPacketType::Unsubscribe,
+                packet_flags: ControlPacketFlags::UNSUBSCRIBE_FLAGS,
+                remaining_length: 0,
+            },
+            packet_id: None,
+            topic_filters: vec![],
+        }
+    }
+
+    pub fn packet_id(&mut self, packet_id: u16) -> &mut Self {
+        self.packet_id = Some(packet_id);
+        self
+    }
+
+    pub fn topic_filter(&mut self, topic_filter: &'a str) -> &mut Self {
+        self.topic_filters.push(topic_filter);
+        self
+    }
+
+    pub fn calc_remaining_length(&self) -> usize {
+        let mut remaining_length = 0;
+        remaining_length += 2;
+        for topic_filter in self.topic_filters.iter() {
+            remaining_length += 2 /* length bytes */ + topic_filter.len();
+        }
+        remaining_length
+    }
+
+    pub fn build(&mut self) -> Result<UnsubscribePacket<'_>, Box<dyn Error>> {
+        let packet_id = match self.packet_id {
+            Some(packet_id) if packet_id != 0 && !self.topic_filters.is_empty() => packet_id,
+            _ => return Err(Box::new(ErrorBuildingUnsubscribePacket)),
+        };
+        self.fixed_header.remaining_length = self.calc_remaining_length();
+        Ok(UnsubscribePacket {
+            fixed_header: self.fixed_header,
+            packet_id,
+            topic_filters: &self.topic_filters,
+        })
+    }
+}
+
+pub struct UnsubscribePacket<'a> {
+    fixed_header: FixedHeader,
+    packet_id: u16,
+    topic_filters: &'a Vec<&'a str>,
+}
+
+impl<'a> Encodable for UnsubscribePacket<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let mut vec: Vec<u8> = Vec::new();
+        vec.extend_from_slice(&self.fixed_header.encode());
+        vec.extend_from_slice(&self.packet_id.to_be_bytes());
+
+        for topic_filter in self.topic_filters.iter() {
+            vec.extend_from_slice(&(topic_filter.len() as u16).to_be_bytes());
+            vec.extend_from_slice(topic_filter.as_bytes());
+        }
+        vec
+    }
+}
+
+#[cfg(test)]
+mod unsubscribe_packet_tests {
+    use crate::control_packets::Encodable;
+
+    #[test]
+    fn encode_test() {
+        let mut builder = super::Builder::new();
+        let unsubscribe_packet = builder.packet_id(3).topic_filter("a/b").build();
+        let unsubscribe_packet_bytes = unsubscribe_packet.unwrap().encode();
+
+        assert_eq!(unsubscribe_packet_bytes[0], 0b1010_0010); // control packet type + reserved
+        assert_eq!(unsubscribe_packet_bytes[1], 7); // remaining length
+        assert_eq!(unsubscribe_packet_bytes[2], 0); // packet id MSB
+        assert_eq!(unsubscribe_packet_bytes[3], 3); // packet id LSB
+        assert_eq!(unsubscribe_packet_bytes[4], 0); // topic filter length MSB
+        assert_eq!(unsubscribe_packet_bytes[5], 3); // topic filter length LSB
+        assert_eq!(&unsubscribe_packet_bytes[6..], b"a/b");
+    }
+
+    #[test]
+    fn empty_build_test() {
+        assert!(super::Builder::new().packet_id(3).build().is_err());
+        assert!(super::Builder::new().topic_filter("a/b").build().is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    io,
+    net::Shutdown,
+    pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    thread,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    task::AtomicWaker,
+    SinkExt, StreamExt,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_uring::net::TcpStream;
+use tracing::debug;
+
+use crate::{
+    mqtt_options::MqttOptions,
+    transport::{self, Connector, Established, UNIX_PREFIX},
+};
+
+// TCP on io_uring, with the io-uring feature on Linux.
+// tokio-uring runs on a runtime of its own, so every connection gets a thread with a ring that
+// owns the socket. The connection task hands it the encoded packets over a channel, and the
+// ring thread submits whatever queued up since its last write as one writev, so writes that
+// queue up before a flush cost one submission instead of one each. A flush waits until the
+// ring thread wrote everything handed to it. What the ring reads comes back over another
+// channel.
+// Proxies, TLS and WebSockets work as they do over epoll, they run on top of the ring's stream.
+
+// How many packets may wait for the ring thread before writes wait
+const WRITE_QUEUE: usize = 1024;
+// The most packets submitted with one writev, Linux limits a writev to 1024 buffers
+const MAX_BATCH: usize = 1024;
+const READ_QUEUE: usize = 16;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+// Connects like the NetworkConnector, but runs the socket I/O on io_uring
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UringConnector;
+
+impl Connector for UringConnector {
+    fn connect<'a>(
+        &'a self,
+        address: &'a str,
+        options: &'a MqttOptions,
+    ) -> BoxFuture<'a, io::Result<Established>> {
+        Box::pin(async move {
+            if address.starts_with(UNIX_PREFIX) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Unix domain sockets aren't supported on io_uring",
+                ));
+            }
+            let (stream, remote_address) = transport::connect_tcp(address, options).await?;
+            let stream = UringStream::spawn(stream.into_std()?).await?;
+            let transport = transport::layer(
+                Box::new(stream),
+                address,
+                options.tls(),
+                options.websocket(),
+            )
+            .await?;
+            Ok(Established::new(transport, Some(remote_address)))
+        })
+    }
+}
+
+// How far the ring thread got with the writes, shared with the stream to wait for on flush
+#[derive(Debug, Default)]
+struct WriteProgress {
+    written: AtomicU64,
+    // the flush waiting for the ring thread, woken after every write and when the ring stops
+    flush: AtomicWaker,
+}
+
+impl WriteProgress {
+    fn wrote(&self, length: usize) {
+        self.written.fetch_add(length as u64, Ordering::Release);
+        self.flush.wake();
+    }
+}
+
+pub(crate) struct UringStream {
+    writes: mpsc::Sender<Vec<u8>>,
+    // bytes handed to the ring thread so far
+    queued: u64,
+    progress: Arc<WriteProgress>,
+    reads: mpsc::Receiver<io::Result<Vec<u8>>>,
+    // what's left of the last read
+    read: Vec<u8>,
+    read_position: usize,
+}
+
+impl UringStream {
+    // Hands the connected socket to a new ring thread
+    async fn spawn(stream: std::net::TcpStream) -> io::Result<UringStream> {
+        let (writes, write_receiver) = mpsc::channel(WRITE_QUEUE);
+        let (read_sender, reads) = mpsc::channel(READ_QUEUE);
+        let (started, start_result) = oneshot::channel();
+        let progress = Arc::new(WriteProgress::default());
+        let ring_progress = Arc::clone(&progress);
+        thread::Builder::new()
+            .name("mqutekitty-uring".to_string())
+            .spawn(move || {
+                let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                    Ok(runtime) => runtime,
+                    Err(error) => {
+                        let _ = started.send(Err(error));
+                        return;
+                    }
+                };
+                let _ = started.send(Ok(()));
+                runtime.block_on(run(
+                    TcpStream::from_std(stream),
+                    write_receiver,
+                    read_sender,
+                    &ring_progress,
+                ));
+            })?;
+        start_result.await.map_err(|_| ring_stopped())??;
+        Ok(UringStream {
+            writes,
+            queued: 0,
+            progress,
+            reads,
+            read: Vec::new(),
+            read_position: 0,
+        })
+    }
+}
+
+// Runs on the ring thread until the stream is dropped or shut down, or the socket fails
+async fn run(
+    stream: TcpStream,
+    writes: mpsc::Receiver<Vec<u8>>,
+    reads: mpsc::Sender<io::Result<Vec<u8>>>,
+    progress: &WriteProgress,
+) {
+    let stream = Rc::new(stream);
+    let reader = tokio_uring::spawn(read_loop(Rc::clone(&stream), reads));
+    match write_loop(&stream, writes, progress).await {
+        Ok((packets, submissions)) => debug!(
+            "io_uring wrote {} packets in {} submissions",
+            packets, submissions
+        ),
+        Err(error) => debug!("io_uring write failed: {}", error),
+    }
+    // the write channel is closed by now, a waiting flush sees that the ring stopped
+    progress.flush.wake();
+    // ends the read that's still waiting
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = reader.await;
+}
+
+// Returns how many packets went out in how many submissions
+async fn write_loop(
+    stream: &TcpStream,
+    mut writes: mpsc::Receiver<Vec<u8>>,
+    progress: &WriteProgress,
+) -> io::Result<(usize, usize)> {
+    let (mut packets, mut submissions) = (0, 0);
+    while let Some(first) = writes.next().await {
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match writes.try_recv() {
+                Ok(packet) => batch.push(packet),
+                Err(_) => break,
+            }
+        }
+        packets += batch.len();
+        submissions += 1;
+        let total: usize = batch.iter().map(Vec::len).sum();
+        let (written, batch) = stream.writev(batch).await;
+        let written = written?;
+        if written < total {
+            // the socket took only part of it, the rest goes out in one piece
+            let rest = batch.concat().split_off(written);
+            submissions += 1;
+            stream.write_all(rest).await.0?;
+        }
+        progress.wrote(total);
+    }
+    Ok((packets, submissions))
+}
+
+async fn read_loop(stream: Rc<TcpStream>, mut reads: mpsc::Sender<io::Result<Vec<u8>>>) {
+    loop {
+        let (read, buffer) = stream.read(Vec::with_capacity(READ_BUFFER_SIZE)).await;
+        let chunk = match read {
+            // the end of the stream, the stream reads it when the channel closes
+            Ok(0) => return,
+            Ok(_) => Ok(buffer),
+            Err(error) => Err(error),
+        };
+        let failed = chunk.is_err();
+        if reads.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// Fails if io_uring can't be used here, e.g. because a container's seccomp profile switches it
+// off. Checked on a thread of its own, a tokio-uring runtime can't be dropped within another
+// runtime.
+pub fn probe() -> io::Result<()> {
+    thread::spawn(|| tokio_uring::Runtime::new(&tokio_uring::builder()).map(drop))
+        .join()
+        .map_err(|_| ring_stopped())?
+}
+
+fn ring_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the io_uring thread stopped")
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_position < this.read.len() {
+                let rest = &this.read[this.read_position..];
+                let length = rest.len().min(buf.remaining());
+                buf.put_slice(&rest[..length]);
+                this.read_position += length;
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.reads.poll_next_unpin(cx)) {
+                Some(Ok(read)) => {
+                    this.read = read;
+                    this.read_position = 0;
+                }
+                Some(Err(error)) => return Poll::Ready(Err(error)),
+                // the end of the stream, like a read of 0 bytes
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.writes.poll_ready(cx)).map_err(|_| ring_stopped())?;
+        this.writes
+            .start_send(buf.to_vec())
+            .map_err(|_| ring_stopped())?;
+        this.queued += buf.len() as u64;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    // Waits until the ring thread wrote everything handed to it. A failed write closes the
+    // channel, so the flush fails instead of waiting forever.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // registered before checking, so a write finishing in between isn't missed
+        self.progress.flush.register(cx.waker());
+        if self.progress.written.load(Ordering::Acquire) >= self.queued {
+            return Poll::Ready(Ok(()));
+        }
+        match self.writes.is_closed() {
+            true => Poll::Ready(Err(ring_stopped())),
+            false => Poll::Pending,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let _ = ready!(self.get_mut().writes.poll_close_unpin(cx));
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod uring_tests {
+    use std::{io, sync::atomic::Ordering};
+
+    use futures::channel::mpsc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tracing::warn;
+
+    use crate::{mqtt_options::MqttOptions, transport::Connector};
+
+    use super::{write_loop, UringConnector, UringStream, WriteProgress};
+
+    // Set where io_uring is known to be missing, e.g. in a container whose seccomp profile
+    // switches it off. Without it a missing io_uring fails the tests instead of passing them.
+    const SKIP_VARIABLE: &str = "MQUTEKITTY_SKIP_URING_TESTS";
+
+    fn uring_available() -> bool {
+        match super::probe() {
+            Ok(()) => true,
+            Err(error) if std::env::var_os(SKIP_VARIABLE).is_some() => {
+                warn!("skipped, io_uring isn't available: {}", error);
+                false
+            }
+            Err(error) => panic!(
+                "io_uring isn't available: {}, set {} to skip the io_uring tests",
+                error, SKIP_VARIABLE
+            ),
+        }
+    }
+
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        (listener, address)
+    }
+
+    #[tokio::test]
+    async fn echo_test() {
+        if !uring_available() {
+            return;
+        }
+        let (listener, address) = listen().await;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = vec![0u8; 100_000];
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+        });
+
+        let options = MqttOptions::new("mqutekitty").unwrap();
+        let established = UringConnector.connect(&address, &options).await.unwrap();
+        assert_eq!(established.remote_address.unwrap().to_string(), address);
+        let mut transport = established.transport;
+        let sent: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        for chunk in sent.chunks(1000) {
+            transport.write_all(chunk).await.unwrap();
+        }
+        transport.flush().await.unwrap();
+        let mut echo = vec![0u8; sent.len()];
+        transport.read_exact(&mut echo).await.unwrap();
+        assert_eq!(echo, sent);
+        server.await.unwrap();
+
+        // the server is gone
+        assert_eq!(transport.read(&mut [0u8; 1]).await.unwrap(), 0);
+        transport.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_ring_test() {
+        if !uring_available() {
+            return;
+        }
+        let (listener, address) = listen().await;
+        let client = std::net::TcpStream::connect(&address).unwrap();
+        let (_server, _) = listener.accept().await.unwrap();
+
+        let mut stream = UringStream::spawn(client).await.unwrap();
+        for chunk in vec![0x42u8; 100_000].chunks(1000) {
+            stream.write_all(chunk).await.unwrap();
+        }
+        stream.flush().await.unwrap();
+        assert_eq!(stream.progress.written.load(Ordering::Acquire), 100_000);
+    }
+
+    #[test]
+    fn batch_test() {
+        if !uring_available() {
+            return;
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio_uring::start(async move {
+            let stream = tokio_uring::net::TcpStream::connect(address).await.unwrap();
+            let (mut server, _) = listener.accept().unwrap();
+
+            // packets that queued up while the ring was busy go out together
+            let (mut writes, receiver) = mpsc::channel(super::WRITE_QUEUE);
+            for i in 0..100u8 {
+                writes.try_send(vec![i; 10]).unwrap();
+            }
+            drop(writes);
+            let progress = WriteProgress::default();
+            let (packets, submissions) = write_loop(&stream, receiver, &progress).await.unwrap();
+            assert_eq!(packets, 100);
+            assert_eq!(submissions, 1);
+            assert_eq!(progress.written.into_inner(), 1000);
+
+            let mut received = vec![0u8; 1000];
+            io::Read::read_exact(&mut server, &mut received).unwrap();
+            assert!(received
+                .chunks(10)
+                .enumerate()
+                .all(|(i, packet)| packet == [i as u8; 10]));
+        });
+    }
+}
@@ -0,0 +1,401 @@
+use std::{
+    error::Error,
+    fmt, io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        http::{HeaderName, HeaderValue},
+        Bytes, Message,
+    },
+    WebSocketStream,
+};
+
+use crate::transport::BoxedTransport;
+
+// MQTT over WebSockets, for brokers that are only reachable through HTTP infrastructure.
+// The client asks for the "mqtt" subprotocol [MQTT-6.0.0-3] and sends every packet in a binary
+// frame [MQTT-6.0.0-1]. Frames don't have to line up with packets, a packet may span several
+// frames and a frame may hold several packets [MQTT-6.0.0-2], so the frames are read as one
+// stream of bytes.
+
+pub const DEFAULT_PATH: &str = "/mqtt";
+const SUBPROTOCOL: &str = "mqtt";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WebSocketError {
+    // the path must start with a /
+    InvalidPath(String),
+    InvalidHeader(String),
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebSocketError::InvalidPath(path) => write!(f, "Invalid WebSocket path {}", path),
+            WebSocketError::InvalidHeader(name) => write!(f, "Invalid HTTP header {}", name),
+        }
+    }
+}
+
+impl Error for WebSocketError {}
+
+#[derive(Debug, Clone)]
+pub struct WebSocketOptions {
+    path: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl WebSocketOptions {
+    // The default path and no extra headers
+    pub fn new() -> WebSocketOptions {
+        Builder::new().build().unwrap()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    // The same headers for another path, which has to start with a /
+    pub(crate) fn with_path(&self, path: &str) -> WebSocketOptions {
+        WebSocketOptions {
+            path: path.to_string(),
+            headers: self.headers.clone(),
+        }
+    }
+}
+
+impl Default for WebSocketOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Builder {
+    path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder {
+            path: DEFAULT_PATH.to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn path(&mut self, path: &str) -> &mut Self {
+        self.path = path.to_string();
+        self
+    }
+
+    // Sent with the upgrade request, e.g. for authenticating with a proxy
+    pub fn header(&mut self, name: &str, value: &str) -> &mut Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn build(&mut self) -> Result<WebSocketOptions, WebSocketError> {
+        if !self.path.starts_with('/') {
+            return Err(WebSocketError::InvalidPath(self.path.clone()));
+        }
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let invalid = || WebSocketError::InvalidHeader(name.clone());
+                Ok((
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?,
+                    HeaderValue::from_str(value).map_err(|_| invalid())?,
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(WebSocketOptions {
+            path: self.path.clone(),
+            headers,
+        })
+    }
+}
+
+pub(crate) struct WebSocket {
+    stream: WebSocketStream<BoxedTransport>,
+    // what's left of the last binary frame
+    frame: Bytes,
+}
+
+impl WebSocket {
+    // Upgrades the connection, secure tells whether it already runs over TLS
+    pub async fn connect(
+        stream: BoxedTransport,
+        address: &str,
+        secure: bool,
+        options: &WebSocketOptions,
+    ) -> io::Result<WebSocket> {
+        let scheme = if secure { "wss" } else { "ws" };
+        let mut request = format!("{}://{}{}", scheme, address, options.path)
+            .into_client_request()
+            .map_err(into_io_error)?;
+        let headers = request.headers_mut();
+        for (name, value) in &options.headers {
+            headers.append(name.clone(), value.clone());
+        }
+        headers.insert(
+            "Sec-WebSocket-Protocol",
+            HeaderValue::from_static(SUBPROTOCOL),
+        );
+        // the handshake fails if the Server doesn't agree to the subprotocol
+        let (stream, _) = tokio_tungstenite::client_async(request, stream)
+            .await
+            .map_err(into_io_error)?;
+        Ok(WebSocket {
+            stream,
+            frame: Bytes::new(),
+        })
+    }
+}
+
+fn into_io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(error) => error,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::NotConnected, error)
+        }
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+impl AsyncRead for WebSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.frame.is_empty() {
+                let length = this.frame.len().min(buf.remaining());
+                buf.put_slice(&this.frame.split_to(length));
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.stream.poll_next_unpin(cx)) {
+                Some(Ok(Message::Binary(frame))) => this.frame = frame,
+                // a Text frame is a protocol violation, the connection has to be closed [MQTT-6.0.0-1]
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "received a text frame",
+                    )))
+                }
+                // the end of the stream, like a read of 0 bytes
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // tungstenite answers pings itself
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WebSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.stream.poll_ready_unpin(cx)).map_err(into_io_error)?;
+        this.stream
+            .start_send_unpin(Message::binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .stream
+            .poll_flush_unpin(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .stream
+            .poll_close_unpin(cx)
+            .map_err(into_io_error)
+    }
+}
+
+#[cfg(test)]
+mod websocket_tests {
+    use std::io;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+    use tokio_tungstenite::{
+        tungstenite::{
+            handshake::server::{Request, Response},
+            http::HeaderValue,
+            Message,
+        },
+        WebSocketStream,
+    };
+
+    use crate::{client_handle::ClientHandle, connect_packet::QoS, mqtt_options, transport};
+
+    use super::{Builder, WebSocketError, WebSocketOptions, DEFAULT_PATH};
+
+    // Accepts one WebSocket connection on /custom that carries the x-token header and asks
+    // for the mqtt subprotocol
+    #[allow(clippy::result_large_err)]
+    async fn accept(listener: TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_hdr_async(stream, |request: &Request, mut response: Response| {
+            assert_eq!(request.uri().path(), "/custom");
+            assert_eq!(request.headers()["x-token"], "secret");
+            assert_eq!(request.headers()["sec-websocket-protocol"], "mqtt");
+            response
+                .headers_mut()
+                .insert("Sec-WebSocket-Protocol", HeaderValue::from_static("mqtt"));
+            Ok(response)
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        (listener, address)
+    }
+
+    fn options() -> WebSocketOptions {
+        Builder::new()
+            .path("/custom")
+            .header("x-token", "secret")
+            .build()
+            .unwrap()
+    }
+
+    fn mqtt_options() -> mqtt_options::MqttOptions {
+        mqtt_options::Builder::new()
+            .client_id("mqutekitty")
+            .websocket(options())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn builder_test() {
+        assert_eq!(WebSocketOptions::new().path(), DEFAULT_PATH);
+        assert_eq!(
+            Builder::new().path("mqtt").build().unwrap_err(),
+            WebSocketError::InvalidPath("mqtt".to_string())
+        );
+        assert_eq!(
+            Builder::new()
+                .header("bad header", "x")
+                .build()
+                .unwrap_err(),
+            WebSocketError::InvalidHeader("bad header".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_test() {
+        let (listener, address) = listen().await;
+        let server: JoinHandle<()> = tokio::spawn(async move {
+            let mut websocket = accept(listener).await;
+            // a CONNACK split over two frames, then the end of a PINGRESP in a frame of its own
+            websocket
+                .send(Message::binary(vec![0x20, 0x02]))
+                .await
+                .unwrap();
+            websocket
+                .send(Message::binary(vec![0x00, 0x00, 0xd0]))
+                .await
+                .unwrap();
+            websocket.send(Message::binary(vec![0x00])).await.unwrap();
+            assert_eq!(
+                websocket.next().await.unwrap().unwrap(),
+                Message::binary(b"ping".to_vec())
+            );
+            websocket.send(Message::text("not mqtt")).await.unwrap();
+        });
+
+        let mut transport = transport::connect(&address, &mqtt_options())
+            .await
+            .unwrap()
+            .transport;
+        let mut packets = [0u8; 6];
+        transport.read_exact(&mut packets).await.unwrap();
+        assert_eq!(packets, [0x20, 0x02, 0x00, 0x00, 0xd0, 0x00]);
+
+        transport.write_all(b"ping").await.unwrap();
+        transport.flush().await.unwrap();
+        let mut byte = [0u8; 1];
+        assert_eq!(
+            transport.read_exact(&mut byte).await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subprotocol_refused_test() {
+        let (listener, address) = listen().await;
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            // accepts the upgrade without agreeing to the mqtt subprotocol
+            let _ = tokio_tungstenite::accept_async(stream).await;
+        });
+
+        assert!(transport::connect(&address, &mqtt_options()).await.is_err());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_over_websocket_test() {
+        let (listener, address) = listen().await;
+        let server = tokio::spawn(async move {
+            let mut websocket = accept(listener).await;
+            let connect = websocket.next().await.unwrap().unwrap().into_data();
+            assert_eq!(connect[0], 0x10);
+            websocket
+                .send(Message::binary(vec![0x20, 0x02, 0x00, 0x00]))
+                .await
+                .unwrap();
+            let publish = websocket.next().await.unwrap().unwrap().into_data();
+            assert_eq!(publish[0], 0x30);
+            assert!(publish.ends_with(b"over websocket"));
+        });
+
+        let options = mqtt_options::Builder::new()
+            .client_id("mqutekitty")
+            .websocket(options())
+            .build()
+            .unwrap();
+        let client = ClientHandle::new(options);
+        client.connect(&address).await.unwrap();
+        client
+            .publish("a/b", b"over websocket", QoS::AtMostOnce)
+            .await
+            .unwrap();
+        server.await.unwrap();
+    }
+}